@@ -0,0 +1,61 @@
+// Compares the three ways of running a compiled program in process: walking the tree, the
+// stack-machine simulator and the closure form, on generated programs of growing size.
+//
+//     cargo bench --bench eval
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use tiny_three_pass_compiler::{simulate, Ast, BinOp, Compiler, Function, Program, Random};
+
+const ARITY: i64 = 4;
+
+// A tree of the given depth whose values stay small enough never to overflow.
+fn generate(random: &mut Random, depth: u32) -> Ast {
+    if depth == 0 {
+        return match random.next(3) {
+            0 => Ast::imm(random.next(100) as i64 + 1),
+            _ => Ast::arg(random.next(ARITY as u64) as i64),
+        };
+    }
+    let (lhs, rhs) = (generate(random, depth - 1), generate(random, depth - 1));
+    match random.next(4) {
+        0 => Ast::cond(Ast::bin(BinOp::Lt, lhs.clone(), rhs.clone()), lhs, rhs),
+        op => {
+            let op = [BinOp::Add, BinOp::Sub, BinOp::Mul][op as usize - 1];
+            Ast::bin(BinOp::Mod, Ast::bin(op, lhs, rhs), Ast::imm(10007))
+        },
+    }
+}
+
+fn measure(name: &str, iterations: usize, f: impl Fn(&[i64]) -> i64) -> i64 {
+    let start = Instant::now();
+    let mut checksum = 0i64;
+    for i in 0..iterations {
+        let args = [i as i64, 3, -7, i as i64 % 13];
+        checksum = checksum.wrapping_add(f(black_box(&args)));
+    }
+    let elapsed: Duration = start.elapsed();
+    println!("    {:<12} {:>10.0} ns/run", name, elapsed.as_nanos() as f64 / iterations as f64);
+    checksum
+}
+
+fn main() {
+    // the same seed every run, so that every run measures the same programs
+    let mut random = Random(42);
+    for &depth in &[8, 10, 12] {
+        let mut compiler = Compiler::new();
+        let program = Program { functions: vec![Function { name: "main".to_string(), arity: ARITY as usize, body: generate(&mut random, depth) }] };
+        let program = compiler.pass2_program(&program).unwrap();
+        let asm = compiler.pass3_program(&program);
+        let closure = compiler.pass3_closure(&program);
+        let iterations = 200_000 >> depth;
+        println!("{} instructions, {} runs:", asm.len(), iterations);
+
+        let tree = measure("tree", iterations, |args| program.eval(args));
+        let simulator = measure("simulator", iterations, |args| simulate(&asm, args));
+        let closures = measure("closures", iterations, |args| closure(args));
+        assert_eq!(tree, simulator);
+        assert_eq!(tree, closures);
+    }
+}
@@ -0,0 +1,128 @@
+// C99 source. The program becomes `int64_t SYMBOL(const int64_t *args)` and every other
+// function `f` of it a static `SYMBOL_f` with the same signature, optionally along with a
+// `main` that reads the arguments from the command line and prints the result.
+//
+// Every node is a C expression: a `let` assigns a local of its own with the comma operator
+// and a conditional is `?:`, which only evaluates the branch taken. Like on the kata machine,
+// what an overflow or a division by zero does is left undefined.
+
+use std::collections::BTreeSet;
+
+use crate::fold::arguments;
+use crate::{Ast, Leaf, Program};
+
+struct Emitter<'a> {
+    symbol: &'a str,
+    // local of each let-bound variable, indexed by its level
+    scope: Vec<usize>,
+    locals: usize,
+}
+
+impl<'a> Emitter<'a> {
+    fn function_symbol(&self, name: &str) -> String {
+        if name == "main" {
+            self.symbol.to_string()
+        } else {
+            format!("{}_{}", self.symbol, name)
+        }
+    }
+
+    fn expression(&mut self, ast: &Ast) -> String {
+        match ast {
+            // -9223372036854775808 would be the negation of a constant too large for any type
+            Ast::UnOp(Leaf::Imm, x) if *x == i64::MIN => "INT64_MIN".to_string(),
+            Ast::UnOp(Leaf::Imm, x) if *x < 0 => format!("({})", x),
+            Ast::UnOp(Leaf::Imm, x) => x.to_string(),
+            Ast::UnOp(Leaf::Arg, index) => format!("args[{}]", index),
+            Ast::UnOp(Leaf::Var, level) => format!("v{}", self.scope[*level as usize]),
+            Ast::UnOp(Leaf::Float, _) => panic!("the C backend has no float mode"),
+            Ast::BinOp(op, lhs, rhs) => format!("({} {} {})", self.expression(lhs), op.symbol(), self.expression(rhs)),
+            Ast::Let(value, body) => {
+                let value = self.expression(value);
+                // an unused value is still evaluated, since it might divide by zero
+                if body.count_uses(self.scope.len() as i64) == 0 {
+                    self.scope.push(usize::MAX);
+                    let body = self.expression(body);
+                    self.scope.pop();
+                    return format!("((void){}, {})", value, body);
+                }
+                let local = self.locals;
+                self.locals += 1;
+                self.scope.push(local);
+                let body = self.expression(body);
+                self.scope.pop();
+                format!("(v{} = {}, {})", local, value, body)
+            },
+            Ast::Call(name, args) if args.is_empty() => format!("{}(NULL)", self.function_symbol(name)),
+            Ast::Call(name, args) => {
+                let args: Vec<String> = args.iter().map(|arg| self.expression(arg)).collect();
+                format!("{}((const int64_t[]){{ {} }})", self.function_symbol(name), args.join(", "))
+            },
+            Ast::If(cond, then, other) => {
+                format!("({} ? {} : {})", self.expression(cond), self.expression(then), self.expression(other))
+            },
+            Ast::At(_, ast) => self.expression(ast),
+        }
+    }
+
+    fn function(&mut self, name: &str, body: &Ast) -> String {
+        self.locals = 0;
+        let expression = self.expression(body);
+        let linkage = if name == "main" { "" } else { "static " };
+        let mut output = format!("{}int64_t {}(const int64_t *args) {{\n", linkage, self.function_symbol(name));
+        if self.locals > 0 {
+            let locals: Vec<String> = (0..self.locals).map(|i| format!("v{}", i)).collect();
+            output.push_str(&format!("    int64_t {};\n", locals.join(", ")));
+        }
+        let mut used = BTreeSet::new();
+        arguments(body, &mut used);
+        if used.is_empty() {
+            output.push_str("    (void)args;\n");
+        }
+        output.push_str(&format!("    return {};\n}}\n", expression));
+        output
+    }
+}
+
+pub(crate) fn emit_program(program: &Program, symbol: &str, with_main: bool) -> String {
+    assert!(!(with_main && symbol == "main"), "the function cannot be called main when there is a main");
+    let mut emitter = Emitter { symbol, scope: Vec::new(), locals: 0 };
+    let mut output = String::from("#include <stddef.h>\n#include <stdint.h>\n");
+    if with_main {
+        output.push_str("#include <inttypes.h>\n#include <stdio.h>\n#include <stdlib.h>\n");
+    }
+    // declared first so that functions may call each other in any order
+    if program.functions.len() > 1 {
+        output.push('\n');
+        for function in program.functions.iter().filter(|f| f.name != "main") {
+            output.push_str(&format!("static int64_t {}(const int64_t *args);\n", emitter.function_symbol(&function.name)));
+        }
+    }
+    for function in &program.functions {
+        output.push('\n');
+        output.push_str(&emitter.function(&function.name, &function.body));
+    }
+    if with_main {
+        let arity = program.function("main").unwrap().arity;
+        output.push_str(&format!(
+            "\nint main(int argc, char **argv) {{\n    \
+                int64_t args[{}] = {{ 0 }};\n    \
+                int i;\n    \
+                if (argc != {}) {{\n        \
+                    fprintf(stderr, \"usage: %s{}\\n\", argv[0]);\n        \
+                    return 2;\n    \
+                }}\n    \
+                for (i = 1; i < argc; i++) {{\n        \
+                    args[i - 1] = strtoll(argv[i], NULL, 10);\n    \
+                }}\n    \
+                printf(\"%\" PRId64 \"\\n\", {}(args));\n    \
+                return 0;\n\
+            }}\n",
+            arity.max(1),
+            arity + 1,
+            " ARG".repeat(arity),
+            symbol,
+        ));
+    }
+    output
+}
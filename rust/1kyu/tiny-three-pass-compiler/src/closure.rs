@@ -0,0 +1,113 @@
+// Compiles a program into nested Rust closures, which evaluate it without decoding anything.
+// Every closure receives the arguments of the current call and the values of the let-bound
+// variables in scope, indexed by level.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+
+use crate::{Ast, BinOp, Closure, Leaf, Program};
+
+type Node = Box<dyn Fn(&[i64], &mut Vec<i64>) -> i64>;
+
+// Callees are looked up when called, since a function may call itself. Only the outermost
+// closure owns the functions so that recursion does not keep them alive forever.
+type Functions = Weak<RefCell<Vec<Node>>>;
+
+fn binary<F: Fn(i64, i64) -> i64 + 'static>(lhs: Node, rhs: Node, f: F) -> Node {
+    Box::new(move |args, vars| {
+        let x = lhs(args, vars);
+        f(x, rhs(args, vars))
+    })
+}
+
+fn compile(ast: &Ast, indices: &HashMap<&str, usize>, functions: &Functions) -> Node {
+    match ast {
+        Ast::UnOp(Leaf::Imm, x) => {
+            let x = *x;
+            Box::new(move |_, _| x)
+        },
+        Ast::UnOp(Leaf::Arg, index) => {
+            let index = *index as usize;
+            Box::new(move |args, _| args[index])
+        },
+        Ast::UnOp(Leaf::Var, level) => {
+            let level = *level as usize;
+            Box::new(move |_, vars| vars[level])
+        },
+        Ast::UnOp(Leaf::Float, _) => panic!("closures compute with i64 and have no float mode"),
+        Ast::BinOp(op, lhs, rhs) => {
+            let (lhs, rhs) = (compile(lhs, indices, functions), compile(rhs, indices, functions));
+            match op {
+                BinOp::Add => binary(lhs, rhs, |x, y| x + y),
+                BinOp::Sub => binary(lhs, rhs, |x, y| x - y),
+                BinOp::Mul => binary(lhs, rhs, |x, y| x * y),
+                BinOp::Div => binary(lhs, rhs, |x, y| x / y),
+                BinOp::Mod => binary(lhs, rhs, |x, y| x % y),
+                BinOp::Lt => binary(lhs, rhs, |x, y| (x < y) as i64),
+                BinOp::Le => binary(lhs, rhs, |x, y| (x <= y) as i64),
+                BinOp::Gt => binary(lhs, rhs, |x, y| (x > y) as i64),
+                BinOp::Ge => binary(lhs, rhs, |x, y| (x >= y) as i64),
+                BinOp::Eq => binary(lhs, rhs, |x, y| (x == y) as i64),
+                BinOp::Ne => binary(lhs, rhs, |x, y| (x != y) as i64),
+            }
+        },
+        Ast::Let(value, body) => {
+            let (value, body) = (compile(value, indices, functions), compile(body, indices, functions));
+            Box::new(move |args, vars| {
+                let x = value(args, vars);
+                vars.push(x);
+                let result = body(args, vars);
+                vars.pop();
+                result
+            })
+        },
+        Ast::Call(name, args) => {
+            let index = indices[name.as_str()];
+            let args: Vec<Node> = args.iter().map(|arg| compile(arg, indices, functions)).collect();
+            let functions = functions.clone();
+            Box::new(move |outer_args, vars| {
+                let values: Vec<i64> = args.iter().map(|arg| arg(outer_args, vars)).collect();
+                let functions = functions.upgrade().unwrap();
+                let functions = functions.borrow();
+                functions[index](&values, &mut Vec::new())
+            })
+        },
+        Ast::If(cond, then, other) => {
+            let cond = compile(cond, indices, functions);
+            let (then, other) = (compile(then, indices, functions), compile(other, indices, functions));
+            Box::new(move |args, vars| if cond(args, vars) != 0 { then(args, vars) } else { other(args, vars) })
+        },
+        Ast::At(_, ast) => compile(ast, indices, functions),
+    }
+}
+
+pub(crate) fn compile_program(program: &Program) -> Closure {
+    let indices: HashMap<&str, usize> = program.functions.iter().enumerate().map(|(i, f)| (f.name.as_str(), i)).collect();
+    let functions = Rc::new(RefCell::new(Vec::new()));
+    let nodes = program.functions.iter().map(|f| compile(&f.body, &indices, &Rc::downgrade(&functions))).collect();
+    *functions.borrow_mut() = nodes;
+    let main = indices["main"];
+    Box::new(move |args| functions.borrow()[main](args, &mut Vec::new()))
+}
+
+#[test]
+fn closures_agree_with_the_tree_and_the_simulator() {
+    use crate::{simulate, Compiler};
+
+    let cases: &[(&str, &[i64])] = &[
+        ("[ x y z ] ( 2*3*x + 5*y - 3*z ) / (1 + 3 + 2*2)", &[4, 8, 16]),
+        ("[ a b ] let s = a + b in let d = a - b in (s * d) / (s - d + 1) + -a % b", &[7, 3]),
+        ("[ a b ] if a <= b then (if a == b then 0 else -1) else a != b", &[9, 4]),
+        ("def fib [n] if n < 2 then n else fib(n - 1) + fib(n - 2); def main [n] fib(n) * (n >= 0)", &[15]),
+    ];
+    let mut compiler = Compiler::new();
+    for (source, args) in cases {
+        let program = compiler.pass1_program(source);
+        let expected = program.eval(args);
+        let program = compiler.pass2_program(&program).unwrap();
+        assert_eq!(program.eval(args), expected, "{}", source);
+        assert_eq!(simulate(&compiler.pass3_program(&program), args), expected, "{}", source);
+        assert_eq!(compiler.pass3_closure(&program)(args), expected, "{}", source);
+    }
+}
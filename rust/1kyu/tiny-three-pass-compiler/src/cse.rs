@@ -0,0 +1,164 @@
+// Common subexpression elimination. The closed subtrees of a function, those without
+// variables or `let`s, are hash-consed bottom-up into a DAG whose nodes are an operator and
+// the ids of its operands, so that equal subtrees get the same id in one pass. Every node that
+// is evaluated whenever the function is, and used more than once, is then computed once in a
+// `let` at the top of the function. Branches of an `if` are not always evaluated.
+
+use std::collections::HashMap;
+
+use crate::{Ast, BinOp, Leaf};
+
+#[derive(PartialEq, Eq, Hash)]
+enum Node {
+    Leaf(Leaf, i64),
+    BinOp(BinOp, usize, usize),
+    Call(String, Vec<usize>),
+    If(usize, usize, usize),
+}
+
+#[derive(Default)]
+struct Dag<'a> {
+    ids: HashMap<Node, usize>,
+    // the first occurrence of each node, with its span if it has one
+    first: Vec<&'a Ast>,
+    // how many evaluated operands refer to each node, counting every node that holds them once
+    uses: Vec<usize>,
+    evaluated: Vec<bool>,
+}
+
+impl<'a> Dag<'a> {
+    // The id of `ast` if it is closed. `evaluated` is whether it is evaluated whenever the
+    // function is.
+    fn visit(&mut self, ast: &'a Ast, evaluated: bool) -> Option<usize> {
+        let node = match ast {
+            Ast::UnOp(Leaf::Var, _) => return None,
+            Ast::UnOp(leaf, x) => Node::Leaf(*leaf, *x),
+            Ast::BinOp(op, lhs, rhs) => {
+                let (lhs, rhs) = (self.visit(lhs, evaluated), self.visit(rhs, evaluated));
+                match (lhs, rhs) {
+                    (Some(lhs), Some(rhs)) => Node::BinOp(*op, lhs, rhs),
+                    _ => return self.open(&[lhs, rhs], evaluated),
+                }
+            },
+            Ast::Let(value, body) => {
+                let (value, body) = (self.visit(value, evaluated), self.visit(body, evaluated));
+                return self.open(&[value, body], evaluated);
+            },
+            Ast::Call(name, args) => {
+                let args: Vec<Option<usize>> = args.iter().map(|arg| self.visit(arg, evaluated)).collect();
+                match args.iter().copied().collect::<Option<Vec<usize>>>() {
+                    Some(ids) => Node::Call(name.clone(), ids),
+                    None => return self.open(&args, evaluated),
+                }
+            },
+            Ast::If(cond, then, other) => {
+                let cond = self.visit(cond, evaluated);
+                let (then, other) = (self.visit(then, false), self.visit(other, false));
+                match (cond, then, other) {
+                    (Some(cond), Some(then), Some(other)) => Node::If(cond, then, other),
+                    _ => return self.open(&[cond], evaluated),
+                }
+            },
+            Ast::At(_, inner) => {
+                let id = self.visit(inner, evaluated)?;
+                if std::ptr::eq(self.first[id], &**inner) {
+                    self.first[id] = ast;
+                }
+                return Some(id);
+            },
+        };
+        let operands = match &node {
+            Node::Leaf(..) => Vec::new(),
+            Node::BinOp(_, lhs, rhs) => vec![*lhs, *rhs],
+            Node::Call(_, args) => args.clone(),
+            Node::If(cond, _, _) => vec![*cond],
+        };
+        let id = match self.ids.get(&node) {
+            Some(&id) => id,
+            None => {
+                let id = self.first.len();
+                self.ids.insert(node, id);
+                self.first.push(ast);
+                self.uses.push(0);
+                self.evaluated.push(false);
+                id
+            },
+        };
+        if evaluated && !self.evaluated[id] {
+            self.evaluated[id] = true;
+            operands.into_iter().for_each(|operand| self.uses[operand] += 1);
+        }
+        Some(id)
+    }
+
+    // A node that is not closed, and is not in the DAG, uses its closed operands every time.
+    fn open(&mut self, operands: &[Option<usize>], evaluated: bool) -> Option<usize> {
+        if evaluated {
+            operands.iter().flatten().for_each(|&operand| self.uses[operand] += 1);
+        }
+        None
+    }
+
+    fn id(&self, node: &Node) -> Option<usize> {
+        self.ids.get(node).copied()
+    }
+
+    // `ast` with the variables shifted by `shift` and every hoisted node but `except` replaced
+    // with its variable, along with the id of `ast` if it is closed.
+    fn rewrite(&self, ast: &Ast, levels: &[Option<i64>], shift: i64, except: Option<usize>) -> (Ast, Option<usize>) {
+        let rewrite = |ast: &Ast| self.rewrite(ast, levels, shift, except);
+        let (result, id) = match ast {
+            Ast::UnOp(Leaf::Var, level) => (Ast::var(level + shift), None),
+            Ast::UnOp(leaf, x) => (ast.clone(), self.id(&Node::Leaf(*leaf, *x))),
+            Ast::BinOp(op, lhs, rhs) => {
+                let ((lhs, l), (rhs, r)) = (rewrite(lhs), rewrite(rhs));
+                let id = l.zip(r).and_then(|(l, r)| self.id(&Node::BinOp(*op, l, r)));
+                (Ast::bin(*op, lhs, rhs), id)
+            },
+            Ast::Let(value, body) => (Ast::let_in(rewrite(value).0, rewrite(body).0), None),
+            Ast::Call(name, args) => {
+                let (args, ids): (Vec<Ast>, Vec<Option<usize>>) = args.iter().map(rewrite).unzip();
+                let id = ids.into_iter().collect::<Option<Vec<usize>>>().and_then(|ids| self.id(&Node::Call(name.clone(), ids)));
+                (Ast::Call(name.clone(), args), id)
+            },
+            Ast::If(cond, then, other) => {
+                let ((cond, c), (then, t), (other, o)) = (rewrite(cond), rewrite(then), rewrite(other));
+                let id = match (c, t, o) {
+                    (Some(c), Some(t), Some(o)) => self.id(&Node::If(c, t, o)),
+                    _ => None,
+                };
+                (Ast::cond(cond, then, other), id)
+            },
+            // replaced along with its span if it is hoisted
+            Ast::At(span, inner) => {
+                let (inner, id) = rewrite(inner);
+                (Ast::at(*span, inner), id)
+            },
+        };
+        match id.filter(|&id| Some(id) != except).and_then(|id| levels[id]) {
+            Some(level) => (Ast::var(level), id),
+            None => (result, id),
+        }
+    }
+}
+
+impl Ast {
+    pub(crate) fn eliminate_common_subtrees(&self) -> Ast {
+        let mut dag = Dag::default();
+        dag.visit(self, true);
+        // operands have smaller ids than the nodes that use them, so they are bound first
+        let hoisted: Vec<usize> = (0..dag.first.len())
+            .filter(|&id| dag.uses[id] > 1 && !matches!(dag.first[id].strip(), Ast::UnOp(..)))
+            .collect();
+        let mut levels = vec![None; dag.first.len()];
+        for (level, &id) in hoisted.iter().enumerate() {
+            levels[id] = Some(level as i64);
+        }
+        let shift = hoisted.len() as i64;
+        let mut result = dag.rewrite(self, &levels, shift, None).0;
+        for &id in hoisted.iter().rev() {
+            result = Ast::let_in(dag.rewrite(dag.first[id], &levels, 0, Some(id)).0, result);
+        }
+        result
+    }
+}
@@ -0,0 +1,202 @@
+// Human-oriented dumps of what each pass produced: indented S-expressions and Graphviz DOT
+// for the ASTs, and a three-address rendering of the stack-machine code.
+
+use crate::{Ast, Leaf, Program, Span};
+
+// S-expressions longer than this are broken over several lines.
+const WIDTH: usize = 80;
+
+impl Ast {
+    fn head_and_children(&self) -> (String, Vec<&Ast>) {
+        match self.strip() {
+            Ast::UnOp(..) => {
+                let text = self.strip().to_string();
+                (text[1..text.len() - 1].to_string(), Vec::new())
+            },
+            Ast::BinOp(op, lhs, rhs) => (op.symbol().to_string(), vec![lhs, rhs]),
+            Ast::Let(value, body) => ("let".to_string(), vec![value, body]),
+            Ast::Call(name, args) => (format!("call {}", name), args.iter().collect()),
+            Ast::If(cond, then, other) => ("if".to_string(), vec![cond, then, other]),
+            Ast::At(..) => unreachable!(),
+        }
+    }
+
+    fn write_pretty(&self, indent: usize, output: &mut String) {
+        let flat = self.to_string();
+        if indent + flat.len() <= WIDTH {
+            output.push_str(&flat);
+            return;
+        }
+        let (head, children) = self.head_and_children();
+        output.push('(');
+        output.push_str(&head);
+        for child in children {
+            output.push('\n');
+            output.push_str(&" ".repeat(indent + 2));
+            child.write_pretty(indent + 2, output);
+        }
+        output.push(')');
+    }
+
+    // The S-expression of `Display`, with every node that does not fit on its line broken
+    // into one child per line.
+    pub fn to_pretty(&self) -> String {
+        let mut output = String::new();
+        self.write_pretty(0, &mut output);
+        output
+    }
+
+    // Returns the id of the node.
+    fn write_dot(&self, prefix: &str, next_id: &mut usize, output: &mut String) -> String {
+        let id = format!("{}{}", prefix, next_id);
+        *next_id += 1;
+        let (head, children) = self.head_and_children();
+        let shape = if let Ast::UnOp(Leaf::Imm, _) | Ast::UnOp(Leaf::Float, _) = self.strip() { "ellipse" } else { "box" };
+        output.push_str(&format!("    {} [label=\"{}\", shape={}];\n", id, head, shape));
+        let labels: &[&str] = match self.strip() {
+            Ast::Let(..) => &["value", "body"],
+            Ast::If(..) => &["cond", "then", "else"],
+            _ => &[],
+        };
+        for (i, child) in children.into_iter().enumerate() {
+            let child_id = child.write_dot(prefix, next_id, output);
+            match labels.get(i) {
+                Some(label) => output.push_str(&format!("    {} -> {} [label=\"{}\"];\n", id, child_id, label)),
+                None => output.push_str(&format!("    {} -> {};\n", id, child_id)),
+            }
+        }
+        id
+    }
+
+    pub fn to_dot(&self) -> String {
+        let mut output = String::from("digraph ast {\n");
+        self.write_dot("n", &mut 0, &mut output);
+        output.push_str("}\n");
+        output
+    }
+}
+
+impl Program {
+    pub fn to_pretty(&self) -> String {
+        let mut output = String::new();
+        for function in &self.functions {
+            output.push_str(&format!("(def {} {}\n  ", function.name, function.arity));
+            function.body.write_pretty(2, &mut output);
+            output.push_str(")\n");
+        }
+        output
+    }
+
+    // One cluster per function.
+    pub fn to_dot(&self) -> String {
+        let mut output = String::from("digraph program {\n");
+        for function in &self.functions {
+            output.push_str(&format!("  subgraph cluster_{} {{\n    label=\"{} ({} arguments)\";\n", function.name, function.name, function.arity));
+            function.body.write_dot(&format!("{}_", function.name), &mut 0, &mut output);
+            output.push_str("  }\n");
+        }
+        output.push_str("}\n");
+        output
+    }
+}
+
+// Spells out what every instruction does to the registers `r0` and `r1` and to the stack
+// slots `s[i]` of the current call. Slot numbers come from a static scan that starts over
+// after each `RT`, which is exact since every function and branch leaves the stack balanced.
+pub fn three_address(asm: &[String]) -> Vec<String> {
+    let width = asm.len().saturating_sub(1).to_string().len();
+    let mut depth = 0usize;
+    asm.iter().enumerate().map(|(address, instr)| {
+        let parts: Vec<&str> = instr.split(' ').collect();
+        let operand = |i: usize| parts.get(i).copied().unwrap_or("?");
+        let binary = |symbol: &str| format!("r0 = r0 {} r1", symbol);
+        let line = match parts[0] {
+            "IM" => format!("r0 = {}", operand(1)),
+            "AR" => format!("r0 = arg[{}]", operand(1)),
+            "SW" => "r0, r1 = r1, r0".to_string(),
+            "PU" => {
+                depth += 1;
+                format!("s[{}] = r0", depth - 1)
+            },
+            "PO" => {
+                depth = depth.saturating_sub(1);
+                format!("r0 = s[{}]", depth)
+            },
+            "LD" => format!("r0 = s[{}]", operand(1)),
+            "ST" => format!("s[{}] = r0", operand(1)),
+            "AD" => binary("+"),
+            "SU" => binary("-"),
+            "MU" => binary("*"),
+            "DI" => binary("/"),
+            "MO" => binary("%"),
+            "LT" => binary("<"),
+            "LE" => binary("<="),
+            "GT" => binary(">"),
+            "GE" => binary(">="),
+            "EQ" => binary("=="),
+            "NE" => binary("!="),
+            "NG" => "r0 = -r0".to_string(),
+            "JP" => format!("goto {}", operand(1)),
+            "JZ" => format!("if r0 == 0 goto {}", operand(1)),
+            "CA" => {
+                let argc: usize = operand(2).parse().unwrap_or(0);
+                depth = depth.saturating_sub(argc);
+                match argc {
+                    0 => format!("r0 = call {}()", operand(1)),
+                    _ => format!("r0 = call {}(s[{}..{}])", operand(1), depth, depth + argc),
+                }
+            },
+            "RT" => {
+                depth = 0;
+                "return r0".to_string()
+            },
+            _ => format!("{} ???", instr),
+        };
+        format!("{:>width$}: {}", address, line, width = width)
+    }).collect()
+}
+
+// The code with the source of each instruction above it, whenever that changes.
+pub fn interleave(source: &str, asm: &[String], spans: &[Option<Span>]) -> String {
+    let mut output = String::new();
+    let mut last = None;
+    for (instr, span) in asm.iter().zip(spans) {
+        if let Some(span) = *span {
+            if last != Some(span) {
+                let (line, column) = span.line_column(source);
+                output.push_str(&format!(";; {}:{}\n", line, column));
+                for text in span.underline(source).lines() {
+                    output.push_str(&format!(";; {}\n", text));
+                }
+                last = Some(span);
+            }
+        }
+        output.push_str(&format!("    {}\n", instr));
+    }
+    output
+}
+
+#[test]
+fn dumps_show_the_structure() {
+    use crate::Compiler;
+
+    let mut compiler = Compiler::new();
+    let ast = compiler.pass1("[ first second third ] (first + second * third) / (first - 2) - (third % second + first * 17 - 3)");
+    assert_eq!(
+        ast.to_pretty(),
+        "(-\n  (/ (+ (arg 0) (* (arg 1) (arg 2))) (- (arg 0) (imm 2)))\n  (- (+ (% (arg 2) (arg 1)) (* (arg 0) (imm 17))) (imm 3)))",
+    );
+    assert_eq!(Ast::imm(1).to_pretty(), "(imm 1)");
+
+    let ast = compiler.pass1("[ a ] if a then 1 else a");
+    assert_eq!(
+        ast.to_dot(),
+        "digraph ast {\n    n0 [label=\"if\", shape=box];\n    n1 [label=\"arg 0\", shape=box];\n    n0 -> n1 [label=\"cond\"];\n    n2 [label=\"imm 1\", shape=ellipse];\n    n0 -> n2 [label=\"then\"];\n    n3 [label=\"arg 0\", shape=box];\n    n0 -> n3 [label=\"else\"];\n}\n",
+    );
+
+    let asm = compiler.compile("def sq [x] x * x; def main [a] let b = sq(a) in if b > 9 then b else 0");
+    let listing = three_address(&asm);
+    assert_eq!(listing.len(), asm.len());
+    assert_eq!(&listing[..4], &[" 0: r0 = arg[0]", " 1: s[0] = r0", " 2: r0 = call 20(s[0..1])", " 3: s[0] = r0"]);
+    assert!(listing.contains(&"19: return r0".to_string()));
+}
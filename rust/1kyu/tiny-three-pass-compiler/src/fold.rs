@@ -0,0 +1,188 @@
+// User-defined transformations of the tree, run by the `PassManager` of the compiler after
+// pass2 and before code generation. A pass implements `AstFold` and overrides the methods of
+// the nodes it cares about; the others rebuild the node from its folded children.
+
+use std::collections::BTreeSet;
+
+use crate::{Ast, BinOp, CompileError, Function, Leaf, Program, Span};
+
+pub trait AstFold {
+    fn fold_function(&mut self, function: &Function) -> Function {
+        Function { body: self.fold(&function.body), ..function.clone() }
+    }
+
+    fn fold(&mut self, ast: &Ast) -> Ast {
+        match ast {
+            Ast::UnOp(leaf, x) => self.fold_leaf(*leaf, *x),
+            Ast::BinOp(op, lhs, rhs) => self.fold_bin(*op, lhs, rhs),
+            Ast::Let(value, body) => self.fold_let(value, body),
+            Ast::Call(name, args) => self.fold_call(name, args),
+            Ast::If(cond, then, other) => self.fold_if(cond, then, other),
+            Ast::At(span, ast) => self.fold_at(*span, ast),
+        }
+    }
+
+    fn fold_leaf(&mut self, leaf: Leaf, x: i64) -> Ast {
+        Ast::UnOp(leaf, x)
+    }
+
+    fn fold_bin(&mut self, op: BinOp, lhs: &Ast, rhs: &Ast) -> Ast {
+        Ast::bin(op, self.fold(lhs), self.fold(rhs))
+    }
+
+    fn fold_let(&mut self, value: &Ast, body: &Ast) -> Ast {
+        Ast::let_in(self.fold(value), self.fold(body))
+    }
+
+    fn fold_call(&mut self, name: &str, args: &[Ast]) -> Ast {
+        Ast::Call(name.to_string(), args.iter().map(|arg| self.fold(arg)).collect())
+    }
+
+    fn fold_if(&mut self, cond: &Ast, then: &Ast, other: &Ast) -> Ast {
+        Ast::cond(self.fold(cond), self.fold(then), self.fold(other))
+    }
+
+    fn fold_at(&mut self, span: Span, ast: &Ast) -> Ast {
+        Ast::at(span, self.fold(ast))
+    }
+}
+
+// `x * 2` and `2 * x` become `x + x` when `x` is a leaf, which costs nothing to load twice.
+pub struct StrengthReduction;
+
+impl AstFold for StrengthReduction {
+    fn fold_bin(&mut self, op: BinOp, lhs: &Ast, rhs: &Ast) -> Ast {
+        let (lhs, rhs) = (self.fold(lhs), self.fold(rhs));
+        let leaf = |ast: &Ast| matches!(ast.strip(), Ast::UnOp(..));
+        match op {
+            BinOp::Mul if rhs.imm_value() == Some(2) && leaf(&lhs) => Ast::bin(BinOp::Add, lhs.clone(), lhs),
+            BinOp::Mul if lhs.imm_value() == Some(2) && leaf(&rhs) => Ast::bin(BinOp::Add, rhs.clone(), rhs),
+            _ => Ast::bin(op, lhs, rhs),
+        }
+    }
+}
+
+pub(crate) fn arguments(ast: &Ast, output: &mut BTreeSet<i64>) {
+    match ast {
+        Ast::UnOp(Leaf::Arg, index) => { output.insert(*index); },
+        Ast::UnOp(..) => (),
+        Ast::BinOp(_, lhs, rhs) | Ast::Let(lhs, rhs) => {
+            arguments(lhs, output);
+            arguments(rhs, output);
+        },
+        Ast::Call(_, args) => args.iter().for_each(|arg| arguments(arg, output)),
+        Ast::If(cond, then, other) => {
+            arguments(cond, output);
+            arguments(then, output);
+            arguments(other, output);
+        },
+        Ast::At(_, ast) => arguments(ast, output),
+    }
+}
+
+// The passes in the order they were added. After each pass, every function must keep its
+// arity and read no argument that it did not read before: a pass may drop a use of an
+// argument, but never invent one or renumber them.
+#[derive(Default)]
+pub struct PassManager {
+    passes: Vec<(String, Box<dyn AstFold>)>,
+}
+
+impl PassManager {
+    pub fn new() -> PassManager {
+        PassManager::default()
+    }
+
+    pub fn add<P: AstFold + 'static>(&mut self, name: &str, pass: P) {
+        self.passes.push((name.to_string(), Box::new(pass)));
+    }
+
+    pub fn run(&mut self, program: &Program) -> Result<Program, CompileError> {
+        let mut program = program.clone();
+        for (name, pass) in &mut self.passes {
+            let mut functions = Vec::new();
+            for function in &program.functions {
+                let folded = pass.fold_function(function);
+                let (mut before, mut after) = (BTreeSet::new(), BTreeSet::new());
+                arguments(&function.body, &mut before);
+                arguments(&folded.body, &mut after);
+                if folded.name != function.name || folded.arity != function.arity {
+                    return Err(CompileError::InvalidPass(format!("{} changed the signature of {}", name, function.name)));
+                }
+                if let Some(index) = after.difference(&before).next() {
+                    return Err(CompileError::InvalidPass(format!("{} made {} read argument {}", name, function.name, index)));
+                }
+                functions.push(folded);
+            }
+            program.functions = functions;
+        }
+        Ok(program)
+    }
+}
+
+#[test]
+fn passes_rewrite_the_tree_and_keep_the_arguments() {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::rc::Rc;
+
+    use crate::{simulate, Compiler};
+
+    // counts the nodes of every function without changing anything
+    #[derive(Default)]
+    struct Census {
+        function: String,
+        nodes: Rc<RefCell<HashMap<String, usize>>>,
+    }
+
+    impl AstFold for Census {
+        fn fold_function(&mut self, function: &Function) -> Function {
+            self.function = function.name.clone();
+            Function { body: self.fold(&function.body), ..function.clone() }
+        }
+
+        fn fold_leaf(&mut self, leaf: Leaf, x: i64) -> Ast {
+            *self.nodes.borrow_mut().entry(self.function.clone()).or_insert(0) += 1;
+            Ast::UnOp(leaf, x)
+        }
+
+        fn fold_bin(&mut self, op: BinOp, lhs: &Ast, rhs: &Ast) -> Ast {
+            *self.nodes.borrow_mut().entry(self.function.clone()).or_insert(0) += 1;
+            Ast::bin(op, self.fold(lhs), self.fold(rhs))
+        }
+    }
+
+    struct SwapArguments;
+
+    impl AstFold for SwapArguments {
+        fn fold_leaf(&mut self, leaf: Leaf, x: i64) -> Ast {
+            match leaf {
+                Leaf::Arg => Ast::arg(x + 1),
+                _ => Ast::UnOp(leaf, x),
+            }
+        }
+    }
+
+    let source = "def twice [x] x * 2; def main [a b] twice(a) + 2 * (a - b) + b * 2";
+    let mut compiler = Compiler::new();
+    let program = compiler.pass1_program(source);
+    let census = Census::default();
+    let nodes = census.nodes.clone();
+    compiler.passes.add("census", census);
+    compiler.passes.add("strength-reduction", StrengthReduction);
+    let reduced = compiler.pass2_program(&program).unwrap();
+    assert_eq!(reduced.function("twice").unwrap().body, Ast::bin(BinOp::Add, Ast::arg(0), Ast::arg(0)));
+    assert_eq!(reduced.function("main").unwrap().body.to_string(), "(+ (+ (call twice (arg 0)) (* (imm 2) (- (arg 0) (arg 1)))) (+ (arg 1) (arg 1)))");
+    assert_eq!(*nodes.borrow(), vec![("twice".to_string(), 3), ("main".to_string(), 11)].into_iter().collect());
+    for args in &[[3, 4], [-7, 2]] {
+        assert_eq!(simulate(&compiler.pass3_program(&reduced), args), program.eval(args));
+    }
+
+    // a lone tree goes through the passes too
+    let ast = compiler.pass1("[ a b ] b * 2 - a");
+    assert_eq!(compiler.pass2(&ast).to_string(), "(- (+ (arg 1) (arg 1)) (arg 0))");
+
+    compiler.passes.add("swap", SwapArguments);
+    assert_eq!(compiler.try_pass2(&ast), Err(CompileError::InvalidPass("swap made main read argument 2".to_string())));
+    assert_eq!(compiler.pass2_program(&program), Err(CompileError::InvalidPass("swap made twice read argument 1".to_string())));
+}
@@ -0,0 +1,295 @@
+// Random testing of the whole compiler. The generator writes well-formed `[ args ] expression`
+// programs from a seed; each one is run on random arguments by walking the pass1 tree, by
+// walking the pass2 tree and by simulating the pass3 code, and all three must agree. A
+// failing program is then shrunk as long as it keeps failing.
+
+use std::fmt;
+
+use crate::{try_simulate, Ast, BinOp, CompileError, Compiler, Function, Leaf, Program, RuntimeError};
+
+// A linear congruential generator, so that a seed always gives the same programs.
+pub struct Random(pub u64);
+
+impl Random {
+    // A number below `bound`.
+    pub fn next(&mut self, bound: u64) -> u64 {
+        self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        (self.0 >> 33) % bound
+    }
+
+    // A number from `low` to `high` inclusive.
+    pub fn range(&mut self, low: i64, high: i64) -> i64 {
+        low + self.next((high - low + 1) as u64) as i64
+    }
+}
+
+const NAMES: [&str; 4] = ["a", "b", "c", "d"];
+
+struct Generator {
+    random: Random,
+    arity: usize,
+    // number of let-bound variables in scope
+    locals: usize,
+}
+
+impl Generator {
+    fn leaf(&mut self) -> String {
+        match self.random.next(8) {
+            0..=2 => {
+                let x = self.random.range(-10, 10);
+                match self.random.next(4) {
+                    0 if x >= 0 => format!("0x{:x}", x),
+                    _ => x.to_string(),
+                }
+            },
+            3 if self.locals > 0 => format!("v{}", self.random.next(self.locals as u64)),
+            _ => NAMES[self.random.next(self.arity as u64) as usize].to_string(),
+        }
+    }
+
+    fn expression(&mut self, depth: u32) -> String {
+        if depth == 0 || self.random.next(6) == 0 {
+            return self.leaf();
+        }
+        match self.random.next(16) {
+            // division and modulo are what constant folding gets wrong most easily
+            0..=7 => {
+                let op = ["+", "-", "*", "/", "%", "/", "%", "*"][self.random.next(8) as usize];
+                format!("({} {} {})", self.expression(depth - 1), op, self.expression(depth - 1))
+            },
+            8..=9 => {
+                let op = ["<", "<=", ">", ">=", "==", "!="][self.random.next(6) as usize];
+                format!("({} {} {})", self.expression(depth - 1), op, self.expression(depth - 1))
+            },
+            10 => format!("-{}", self.expression(depth - 1)),
+            11..=13 => {
+                let value = self.expression(depth - 1);
+                let name = format!("v{}", self.locals);
+                self.locals += 1;
+                let body = self.expression(depth - 1);
+                self.locals -= 1;
+                format!("(let {} = {} in {})", name, value, body)
+            },
+            _ => {
+                let cond = self.expression(depth - 1);
+                format!("(if {} then {} else {})", cond, self.expression(depth - 1), self.expression(depth - 1))
+            },
+        }
+    }
+}
+
+// The source of a random program with one to four arguments.
+pub fn random_program(seed: u64, depth: u32) -> String {
+    let mut random = Random(seed);
+    let arity = random.range(1, NAMES.len() as i64) as usize;
+    let mut generator = Generator { random, arity, locals: 0 };
+    format!("[ {} ] {}", NAMES[..arity].join(" "), generator.expression(depth))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome {
+    Value(i64),
+    DivisionByZero,
+    Overflow,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Outcome::Value(x) => write!(f, "{}", x),
+            Outcome::DivisionByZero => write!(f, "division by zero"),
+            Outcome::Overflow => write!(f, "overflow"),
+        }
+    }
+}
+
+// What running the tree means, with the traps that `Program::eval` would panic on.
+fn evaluate(ast: &Ast, args: &[i64], vars: &mut Vec<i64>) -> Result<i64, Outcome> {
+    match ast {
+        Ast::UnOp(Leaf::Imm, x) => Ok(*x),
+        Ast::UnOp(Leaf::Arg, index) => Ok(args[*index as usize]),
+        Ast::UnOp(Leaf::Var, level) => Ok(vars[*level as usize]),
+        Ast::BinOp(op, lhs, rhs) => {
+            let x = evaluate(lhs, args, vars)?;
+            let y = evaluate(rhs, args, vars)?;
+            match op {
+                BinOp::Div | BinOp::Mod if y == 0 => Err(Outcome::DivisionByZero),
+                BinOp::Add => x.checked_add(y).ok_or(Outcome::Overflow),
+                BinOp::Sub => x.checked_sub(y).ok_or(Outcome::Overflow),
+                BinOp::Mul => x.checked_mul(y).ok_or(Outcome::Overflow),
+                BinOp::Div => x.checked_div(y).ok_or(Outcome::Overflow),
+                BinOp::Mod => x.checked_rem(y).ok_or(Outcome::Overflow),
+                _ => Ok(op.evaluate(x, y)),
+            }
+        },
+        Ast::Let(value, body) => {
+            let x = evaluate(value, args, vars)?;
+            vars.push(x);
+            let result = evaluate(body, args, vars);
+            vars.pop();
+            result
+        },
+        Ast::If(cond, then, other) => match evaluate(cond, args, vars)? {
+            0 => evaluate(other, args, vars),
+            _ => evaluate(then, args, vars),
+        },
+        Ast::At(_, ast) => evaluate(ast, args, vars),
+        Ast::UnOp(Leaf::Float, _) | Ast::Call(..) => unreachable!("generated programs have no floats or calls"),
+    }
+}
+
+fn outcome(ast: &Ast, args: &[i64]) -> Outcome {
+    evaluate(ast, args, &mut Vec::new()).map_or_else(|trap| trap, Outcome::Value)
+}
+
+// A program whose stages disagree, after shrinking.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Failure {
+    pub program: Ast,
+    pub args: Vec<i64>,
+    pub stage: &'static str,
+    pub expected: Outcome,
+    pub actual: Outcome,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} with arguments {:?}: {} after {}, expected {}", self.program, self.args, self.actual, self.stage, self.expected)
+    }
+}
+
+fn kata_program(body: &Ast, arity: usize) -> Program {
+    Program { functions: vec![Function { name: "main".to_string(), arity, body: body.clone() }] }
+}
+
+// None when the stages agree, or when the case says nothing: the program overflows, which
+// the stages need not agree on, or pass2 rejects it for dividing a constant by zero.
+fn check(compiler: &mut Compiler, body: &Ast, args: &[i64]) -> Option<Failure> {
+    let failure = |stage, expected, actual| Some(Failure { program: body.clone(), args: args.to_vec(), stage, expected, actual });
+    let program = kata_program(body, args.len());
+    let expected = outcome(body, args);
+    match expected {
+        Outcome::Overflow => return None,
+        Outcome::Value(x) if program.eval(args) != x => return failure("pass1", expected, Outcome::Value(program.eval(args))),
+        _ => (),
+    }
+
+    let program = match compiler.pass2_program(&program) {
+        Ok(program) => program,
+        Err(CompileError::DivisionByZero(_)) | Err(CompileError::Overflow(_)) => return None,
+        Err(e) => panic!("{}", e),
+    };
+    let actual = outcome(&program.functions[0].body, args);
+    if actual != expected {
+        return failure("pass2", expected, actual);
+    }
+
+    let actual = match try_simulate(&compiler.pass3_program(&program), args) {
+        Ok(x) => Outcome::Value(x),
+        Err(RuntimeError::DivisionByZero { .. }) => Outcome::DivisionByZero,
+        Err(RuntimeError::Overflow { .. }) => Outcome::Overflow,
+    };
+    if actual != expected {
+        return failure("pass3", expected, actual);
+    }
+    None
+}
+
+// Every tree one step simpler than `ast`, at let depth `depth`: a node replaced with one of
+// its children or a constant closer to 0, or a let that is not used replaced with its body.
+fn shrink(ast: &Ast, depth: i64) -> Vec<Ast> {
+    let mut candidates = Vec::new();
+    match ast {
+        Ast::UnOp(Leaf::Imm, x) => {
+            // only ever closer to 0, so that shrinking terminates
+            candidates.extend([0, 1, x / 2].iter().filter(|y| y.abs() < x.abs()).map(|&y| Ast::imm(y)));
+            return candidates;
+        },
+        Ast::UnOp(..) => return vec![Ast::imm(0), Ast::imm(1)],
+        Ast::At(_, ast) => {
+            candidates.push(ast.as_ref().clone());
+            candidates.extend(shrink(ast, depth));
+            return candidates;
+        },
+        _ => candidates.extend([Ast::imm(0), Ast::imm(1)].iter().cloned()),
+    }
+    match ast {
+        Ast::BinOp(op, lhs, rhs) => {
+            candidates.push(lhs.as_ref().clone());
+            candidates.push(rhs.as_ref().clone());
+            candidates.extend(shrink(lhs, depth).into_iter().map(|lhs| Ast::bin(*op, lhs, rhs.as_ref().clone())));
+            candidates.extend(shrink(rhs, depth).into_iter().map(|rhs| Ast::bin(*op, lhs.as_ref().clone(), rhs)));
+        },
+        Ast::Let(value, body) => {
+            candidates.push(value.as_ref().clone());
+            if body.count_uses(depth) == 0 {
+                candidates.push(body.shift(depth + 1, -1));
+            }
+            candidates.extend(shrink(value, depth).into_iter().map(|value| Ast::let_in(value, body.as_ref().clone())));
+            candidates.extend(shrink(body, depth + 1).into_iter().map(|body| Ast::let_in(value.as_ref().clone(), body)));
+        },
+        Ast::If(cond, then, other) => {
+            candidates.extend([cond, then, other].iter().map(|ast| ast.as_ref().clone()));
+            candidates.extend(shrink(cond, depth).into_iter().map(|cond| Ast::cond(cond, then.as_ref().clone(), other.as_ref().clone())));
+            candidates.extend(shrink(then, depth).into_iter().map(|then| Ast::cond(cond.as_ref().clone(), then, other.as_ref().clone())));
+            candidates.extend(shrink(other, depth).into_iter().map(|other| Ast::cond(cond.as_ref().clone(), then.as_ref().clone(), other)));
+        },
+        _ => unreachable!(),
+    }
+    candidates
+}
+
+// Takes the first simpler tree that still fails, in whatever way, until there is none.
+fn minimize(compiler: &mut Compiler, mut failure: Failure) -> Failure {
+    'shrinking: loop {
+        for candidate in shrink(&failure.program, 0) {
+            if let Some(smaller) = check(compiler, &candidate, &failure.args) {
+                failure = smaller;
+                continue 'shrinking;
+            }
+        }
+        return failure;
+    }
+}
+
+// Checks `cases` programs of the given depth, each on a few argument vectors, and returns
+// the first failure once minimized.
+pub fn fuzz(compiler: &mut Compiler, seed: u64, cases: usize, depth: u32) -> Result<(), Failure> {
+    let mut random = Random(seed);
+    for _ in 0..cases {
+        let source = random_program(random.next(u64::MAX), depth);
+        let body = compiler.pass1(&source);
+        for _ in 0..4 {
+            let args: Vec<i64> = (0..compiler.args.len()).map(|_| random.range(-20, 20)).collect();
+            if let Some(failure) = check(compiler, &body, &args) {
+                return Err(minimize(compiler, failure));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn fuzzing_finds_and_shrinks_a_miscompilation() {
+    use crate::AstFold;
+
+    assert_eq!(random_program(7, 4), random_program(7, 4));
+    assert!(fuzz(&mut Compiler::new(), 1, 200, 5).is_ok());
+
+    // turns every `%` into `/`
+    struct Broken;
+
+    impl AstFold for Broken {
+        fn fold_bin(&mut self, op: BinOp, lhs: &Ast, rhs: &Ast) -> Ast {
+            let op = if op == BinOp::Mod { BinOp::Div } else { op };
+            Ast::bin(op, self.fold(lhs), self.fold(rhs))
+        }
+    }
+
+    let mut compiler = Compiler::new();
+    compiler.passes.add("broken", Broken);
+    let failure = fuzz(&mut compiler, 1, 200, 5).unwrap_err();
+    assert_eq!(failure.stage, "pass2");
+    assert!(failure.program.size() <= 3, "{}", failure);
+    assert!(failure.program.to_string().starts_with("(%"), "{}", failure);
+}
@@ -0,0 +1,459 @@
+// The kata's JSON form of the AST, e.g. `{"op":"+","a":{"op":"arg","n":0},"b":{"op":"imm","n":5}}`.
+// Calls are written `{"op":"call","name":"f","args":[...]}`, conditionals `{"op":"if","a":...,"b":...,"c":...}`
+// with the condition in "a", and a whole program is an array of `{"name":"f","arity":2,"body":...}`.
+// Literals of the float mode are `{"op":"float","n":0.1}`, with "NaN", "Infinity" or "-Infinity"
+// in "n" for what constant folding may make of them but JSON numbers cannot say.
+
+use std::convert::TryFrom;
+use std::iter::Peekable;
+use std::str::CharIndices;
+
+use crate::{Ast, CompileError, Function, Leaf, Program, TaggedAst};
+
+enum Json {
+    Object(Vec<(String, Json)>),
+    Array(Vec<Json>),
+    String(String),
+    Number(i64),
+    Float(f64),
+}
+
+impl Json {
+    fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    iter: Peekable<CharIndices<'a>>,
+}
+
+impl<'a> Parser<'a> {
+    fn new(text: &'a str) -> Parser<'a> {
+        Parser { text, iter: text.char_indices().peekable() }
+    }
+
+    fn offset(&mut self) -> usize {
+        match self.iter.peek() {
+            Some(&(i, _)) => i,
+            None => self.text.len(),
+        }
+    }
+
+    fn error(&mut self, message: &str) -> CompileError {
+        CompileError::InvalidJson(format!("{} at offset {}", message, self.offset()))
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(&(_, c)) = self.iter.peek() {
+            if !c.is_whitespace() {
+                break;
+            }
+            self.iter.next();
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), CompileError> {
+        self.skip_whitespace();
+        match self.iter.peek() {
+            Some(&(_, c)) if c == expected => {
+                self.iter.next();
+                Ok(())
+            },
+            _ => Err(self.error(&format!("expected '{}'", expected))),
+        }
+    }
+
+    fn parse_document(&mut self) -> Result<Json, CompileError> {
+        let value = self.parse_value()?;
+        self.skip_whitespace();
+        if self.iter.peek().is_some() {
+            return Err(self.error("trailing characters"));
+        }
+        Ok(value)
+    }
+
+    fn parse_value(&mut self) -> Result<Json, CompileError> {
+        self.skip_whitespace();
+        match self.iter.peek() {
+            Some(&(_, '{')) => self.parse_object(),
+            Some(&(_, '[')) => self.parse_array(),
+            Some(&(_, '"')) => Ok(Json::String(self.parse_string()?)),
+            Some(&(_, c)) if c == '-' || c.is_ascii_digit() => self.parse_number(),
+            _ => Err(self.error("expected an object, an array, a string or a number")),
+        }
+    }
+
+    fn parse_object(&mut self) -> Result<Json, CompileError> {
+        let mut fields = Vec::new();
+        self.expect('{')?;
+        self.skip_whitespace();
+        if let Some(&(_, '}')) = self.iter.peek() {
+            self.iter.next();
+            return Ok(Json::Object(fields));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = self.parse_string()?;
+            self.expect(':')?;
+            fields.push((key, self.parse_value()?));
+            self.skip_whitespace();
+            match self.iter.peek() {
+                Some(&(_, ',')) => {
+                    self.iter.next();
+                },
+                Some(&(_, '}')) => {
+                    self.iter.next();
+                    return Ok(Json::Object(fields));
+                },
+                _ => return Err(self.error("expected ',' or '}'")),
+            }
+        }
+    }
+
+    fn parse_array(&mut self) -> Result<Json, CompileError> {
+        let mut elements = Vec::new();
+        self.expect('[')?;
+        self.skip_whitespace();
+        if let Some(&(_, ']')) = self.iter.peek() {
+            self.iter.next();
+            return Ok(Json::Array(elements));
+        }
+        loop {
+            elements.push(self.parse_value()?);
+            self.skip_whitespace();
+            match self.iter.peek() {
+                Some(&(_, ',')) => {
+                    self.iter.next();
+                },
+                Some(&(_, ']')) => {
+                    self.iter.next();
+                    return Ok(Json::Array(elements));
+                },
+                _ => return Err(self.error("expected ',' or ']'")),
+            }
+        }
+    }
+
+    fn parse_string(&mut self) -> Result<String, CompileError> {
+        self.expect('"')?;
+        let mut result = String::new();
+        loop {
+            match self.iter.next() {
+                Some((_, '"')) => return Ok(result),
+                Some((_, '\\')) => match self.iter.next() {
+                    Some((_, '"')) => result.push('"'),
+                    Some((_, '\\')) => result.push('\\'),
+                    Some((_, '/')) => result.push('/'),
+                    Some((_, 'n')) => result.push('\n'),
+                    Some((_, 't')) => result.push('\t'),
+                    Some((_, 'r')) => result.push('\r'),
+                    Some((_, 'u')) => {
+                        let mut code = 0u32;
+                        for _ in 0..4 {
+                            match self.iter.next().and_then(|(_, c)| c.to_digit(16)) {
+                                Some(digit) => code = code * 16 + digit,
+                                None => return Err(self.error("invalid unicode escape")),
+                            }
+                        }
+                        match std::char::from_u32(code) {
+                            Some(c) => result.push(c),
+                            None => return Err(self.error("invalid unicode escape")),
+                        }
+                    },
+                    _ => return Err(self.error("invalid escape sequence")),
+                },
+                Some((_, c)) => result.push(c),
+                None => return Err(self.error("unterminated string")),
+            }
+        }
+    }
+
+    fn parse_number(&mut self) -> Result<Json, CompileError> {
+        let start = self.offset();
+        let mut decimal = false;
+        if let Some(&(_, '-')) = self.iter.peek() {
+            self.iter.next();
+        }
+        while let Some(&(i, c)) = self.iter.peek() {
+            let sign = (c == '+' || c == '-') && matches!(self.text[..i].chars().last(), Some('e' | 'E'));
+            if !c.is_ascii_digit() && c != '.' && c != 'e' && c != 'E' && !sign {
+                break;
+            }
+            decimal |= !c.is_ascii_digit();
+            self.iter.next();
+        }
+        let text = &self.text[start..self.offset()];
+        match (decimal, text.parse(), text.parse()) {
+            (false, Ok(n), _) => Ok(Json::Number(n)),
+            (true, _, Ok(x)) => Ok(Json::Float(x)),
+            _ => Err(CompileError::InvalidJson(format!("invalid number at offset {}", start))),
+        }
+    }
+}
+
+fn to_tagged(json: &Json) -> Result<TaggedAst, CompileError> {
+    let invalid = |message: &str| CompileError::InvalidJson(message.to_string());
+    let op = match json.get("op") {
+        Some(Json::String(op)) => op.clone(),
+        _ => return Err(invalid("node without a string \"op\"")),
+    };
+    if op == "call" {
+        return match (json.get("name"), json.get("args")) {
+            (Some(Json::String(name)), Some(Json::Array(args))) => {
+                Ok(TaggedAst::Call(name.clone(), args.iter().map(to_tagged).collect::<Result<_, _>>()?))
+            },
+            _ => Err(invalid("call needs a string \"name\" and an array of \"args\"")),
+        };
+    }
+    if op == "if" {
+        return match (json.get("a"), json.get("b"), json.get("c")) {
+            (Some(a), Some(b), Some(c)) => {
+                Ok(TaggedAst::If(Box::new(to_tagged(a)?), Box::new(to_tagged(b)?), Box::new(to_tagged(c)?)))
+            },
+            _ => Err(invalid("if needs \"a\", \"b\" and \"c\"")),
+        };
+    }
+    match (json.get("n"), json.get("a"), json.get("b")) {
+        (Some(n), None, None) if op == "float" => match float_value(n) {
+            Some(x) => Ok(TaggedAst::UnOp(op, x.to_bits() as i64)),
+            None => Err(invalid("float needs a number \"n\"")),
+        },
+        (Some(Json::Number(n)), None, None) => Ok(TaggedAst::UnOp(op, *n)),
+        (None, Some(a), Some(b)) => Ok(TaggedAst::BinOp(op, Box::new(to_tagged(a)?), Box::new(to_tagged(b)?))),
+        _ => Err(invalid(&format!("node {:?} needs either \"n\" or both \"a\" and \"b\"", op))),
+    }
+}
+
+fn float_value(json: &Json) -> Option<f64> {
+    match json {
+        Json::Number(n) => Some(*n as f64),
+        Json::Float(x) => Some(*x),
+        Json::String(s) if s == "NaN" => Some(f64::NAN),
+        Json::String(s) if s == "Infinity" => Some(f64::INFINITY),
+        Json::String(s) if s == "-Infinity" => Some(f64::NEG_INFINITY),
+        _ => None,
+    }
+}
+
+// The shortest decimal that reads back as the same f64.
+fn write_float(x: f64, output: &mut String) {
+    match x {
+        _ if x.is_nan() => output.push_str("\"NaN\""),
+        _ if x.is_infinite() => output.push_str(if x > 0.0 { "\"Infinity\"" } else { "\"-Infinity\"" }),
+        _ => output.push_str(&format!("{:?}", x)),
+    }
+}
+
+// What pass1 guarantees of its trees and the other passes rely on: every variable is bound by
+// a `let` around it, and arguments are below the arity, when it is known. Calls are checked
+// against the program by `Program::check`, and a tree on its own may make none.
+fn check_scope(ast: &Ast, arity: Option<usize>, depth: i64) -> Result<(), CompileError> {
+    let invalid = |message: String| Err(CompileError::InvalidJson(message));
+    match ast {
+        Ast::UnOp(Leaf::Var, level) if *level < 0 || *level >= depth => invalid(format!("variable {} is not bound", level)),
+        Ast::UnOp(Leaf::Arg, index) if *index < 0 || arity.is_some_and(|arity| *index as usize >= arity) => {
+            invalid(format!("argument {} does not exist", index))
+        },
+        Ast::UnOp(..) => Ok(()),
+        Ast::BinOp(_, lhs, rhs) => {
+            check_scope(lhs, arity, depth)?;
+            check_scope(rhs, arity, depth)
+        },
+        Ast::Let(value, body) => {
+            check_scope(value, arity, depth)?;
+            check_scope(body, arity, depth + 1)
+        },
+        Ast::Call(name, _) if arity.is_none() => invalid(format!("call of {} outside a program", name)),
+        Ast::Call(_, args) => args.iter().try_for_each(|arg| check_scope(arg, arity, depth)),
+        Ast::If(cond, then, other) => {
+            check_scope(cond, arity, depth)?;
+            check_scope(then, arity, depth)?;
+            check_scope(other, arity, depth)
+        },
+        Ast::At(_, ast) => check_scope(ast, arity, depth),
+    }
+}
+
+// Function names end up in the labels of the stack machine code, so they must be what the
+// lexer reads as an identifier.
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some('a'..='z' | 'A'..='Z' | '_')) && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn write_string(s: &str, output: &mut String) {
+    output.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => output.push_str("\\\""),
+            '\\' => output.push_str("\\\\"),
+            _ => output.push(c),
+        }
+    }
+    output.push('"');
+}
+
+impl TaggedAst {
+    fn write_json(&self, output: &mut String) {
+        output.push_str("{\"op\":");
+        match self {
+            TaggedAst::BinOp(op, a, b) => {
+                write_string(op, output);
+                output.push_str(",\"a\":");
+                a.write_json(output);
+                output.push_str(",\"b\":");
+                b.write_json(output);
+            },
+            TaggedAst::UnOp(op, n) if op == "float" => {
+                output.push_str("\"float\",\"n\":");
+                write_float(f64::from_bits(*n as u64), output);
+            },
+            TaggedAst::UnOp(op, n) => {
+                write_string(op, output);
+                output.push_str(&format!(",\"n\":{}", n));
+            },
+            TaggedAst::Call(name, args) => {
+                output.push_str("\"call\",\"name\":");
+                write_string(name, output);
+                output.push_str(",\"args\":[");
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        output.push(',');
+                    }
+                    arg.write_json(output);
+                }
+                output.push(']');
+            },
+            TaggedAst::If(a, b, c) => {
+                output.push_str("\"if\",\"a\":");
+                a.write_json(output);
+                output.push_str(",\"b\":");
+                b.write_json(output);
+                output.push_str(",\"c\":");
+                c.write_json(output);
+            },
+        }
+        output.push('}');
+    }
+}
+
+impl Ast {
+    pub fn to_json(&self) -> String {
+        let mut output = String::new();
+        TaggedAst::from(self).write_json(&mut output);
+        output
+    }
+
+    pub fn from_json(text: &str) -> Result<Ast, CompileError> {
+        let json = Parser::new(text).parse_document()?;
+        let ast = Ast::try_from(&to_tagged(&json)?)?;
+        check_scope(&ast, None, 0)?;
+        Ok(ast)
+    }
+}
+
+impl Program {
+    pub fn to_json(&self) -> String {
+        let mut output = String::from("[");
+        for (i, function) in self.functions.iter().enumerate() {
+            if i > 0 {
+                output.push(',');
+            }
+            output.push_str("{\"name\":");
+            write_string(&function.name, &mut output);
+            output.push_str(&format!(",\"arity\":{},\"body\":", function.arity));
+            TaggedAst::from(&function.body).write_json(&mut output);
+            output.push('}');
+        }
+        output.push(']');
+        output
+    }
+
+    pub fn from_json(text: &str) -> Result<Program, CompileError> {
+        let invalid = |message: &str| CompileError::InvalidJson(message.to_string());
+        let functions = match Parser::new(text).parse_document()? {
+            Json::Array(functions) => functions,
+            _ => return Err(invalid("a program is an array of functions")),
+        };
+        let functions = functions.iter().map(|f| match (f.get("name"), f.get("arity"), f.get("body")) {
+            (Some(Json::String(name)), Some(&Json::Number(arity)), Some(body)) if arity >= 0 => Ok(Function {
+                name: name.clone(),
+                arity: arity as usize,
+                body: Ast::try_from(&to_tagged(body)?)?,
+            }),
+            _ => Err(invalid("a function needs a string \"name\", an \"arity\" and a \"body\"")),
+        }).collect::<Result<Vec<Function>, _>>()?;
+        for function in &functions {
+            if !is_identifier(&function.name) {
+                return Err(CompileError::InvalidJson(format!("{:?} is not a valid function name", function.name)));
+            }
+            check_scope(&function.body, Some(function.arity), 0)?;
+        }
+        let program = Program { functions };
+        program.check().map_err(CompileError::InvalidJson)?;
+        Ok(program)
+    }
+}
+
+#[test]
+fn json_accepts_any_layout_and_reports_errors() {
+    let ast = Ast::from_json(r#" { "b" : {"n": -3, "op": "imm"}, "op": "*", "a": {"op":"arg","n":1} } "#);
+    assert_eq!(ast.map(|ast| ast.to_json()), Ok(r#"{"op":"*","a":{"op":"arg","n":1},"b":{"op":"imm","n":-3}}"#.to_string()));
+
+    assert_eq!(Ast::from_json(r#"{"op":"imm","n":1"#), Err(CompileError::InvalidJson("expected ',' or '}' at offset 17".to_string())));
+    assert_eq!(Ast::from_json(r#"{"op":"^","a":{"op":"imm","n":1},"b":{"op":"imm","n":2}}"#), Err(CompileError::UnknownOperator("^".to_string())));
+}
+
+#[test]
+fn json_trees_are_checked_before_the_passes_see_them() {
+    let invalid = |message: &str| CompileError::InvalidJson(message.to_string());
+    assert_eq!(Ast::from_json(r#"{"op":"var","n":3}"#), Err(invalid("variable 3 is not bound")));
+    assert_eq!(Ast::from_json(r#"{"op":"let","a":{"op":"imm","n":1},"b":{"op":"var","n":1}}"#), Err(invalid("variable 1 is not bound")));
+    assert_eq!(Ast::from_json(r#"{"op":"arg","n":-1}"#), Err(invalid("argument -1 does not exist")));
+    assert_eq!(Ast::from_json(r#"{"op":"call","name":"f","args":[]}"#), Err(invalid("call of f outside a program")));
+    assert!(Ast::from_json(r#"{"op":"let","a":{"op":"arg","n":7},"b":{"op":"var","n":0}}"#).is_ok());
+
+    let program = |body: &str| Program::from_json(&format!(r#"[{{"name":"main","arity":1,"body":{}}}]"#, body));
+    assert_eq!(program(r#"{"op":"arg","n":1}"#), Err(invalid("argument 1 does not exist")));
+    assert_eq!(program(r#"{"op":"call","name":"g","args":[]}"#), Err(invalid("undefined function g")));
+    assert_eq!(program(r#"{"op":"call","name":"main","args":[]}"#), Err(invalid("main takes 1 arguments but 0 were given")));
+    assert_eq!(Program::from_json(r#"[{"name":"f","arity":0,"body":{"op":"imm","n":1}}]"#), Err(invalid("program has no main function")));
+    for name in &["a b", "f.g", "1f", ""] {
+        let text = format!(r#"[{{"name":"{}","arity":0,"body":{{"op":"imm","n":1}}}},{{"name":"main","arity":0,"body":{{"op":"imm","n":2}}}}]"#, name);
+        assert_eq!(Program::from_json(&text), Err(CompileError::InvalidJson(format!("{:?} is not a valid function name", name))));
+    }
+}
+
+#[test]
+fn json_floats_are_decimal_numbers() {
+    assert_eq!(Ast::float(0.1).to_json(), r#"{"op":"float","n":0.1}"#);
+    for &x in &[0.1, -0.0, 2.5e-300, 1e300, f64::MAX, f64::INFINITY, f64::NEG_INFINITY, f64::NAN] {
+        assert_eq!(Ast::from_json(&Ast::float(x).to_json()), Ok(Ast::float(x)), "{}", x);
+    }
+    assert_eq!(Ast::from_json(r#"{"op":"float","n":2}"#), Ok(Ast::float(2.0)));
+    assert_eq!(Ast::from_json(r#"{"op":"float","n":-1.5E+2}"#), Ok(Ast::float(-150.0)));
+    assert_eq!(Ast::from_json(r#"{"op":"float","n":"1"}"#), Err(CompileError::InvalidJson("float needs a number \"n\"".to_string())));
+    assert!(Ast::from_json(r#"{"op":"imm","n":0.5}"#).is_err());
+    assert_eq!(Ast::from_json(r#"{"op":"float","n":1.2.3}"#), Err(CompileError::InvalidJson("invalid number at offset 18".to_string())));
+}
+
+#[test]
+fn json_programs_round_trip() {
+    let text = r#"[{"name":"sq","arity":1,"body":{"op":"*","a":{"op":"arg","n":0},"b":{"op":"arg","n":0}}},{"name":"main","arity":1,"body":{"op":"call","name":"sq","args":[{"op":"arg","n":0}]}}]"#;
+    let program = Program::from_json(text).unwrap();
+    assert_eq!(program.functions[1].body, Ast::Call("sq".to_string(), vec![Ast::arg(0)]));
+    assert_eq!(program.to_json(), text);
+}
+
+#[test]
+fn json_conditionals_round_trip() {
+    let text = r#"{"op":"if","a":{"op":"<","a":{"op":"arg","n":0},"b":{"op":"imm","n":0}},"b":{"op":"imm","n":-1},"c":{"op":"imm","n":1}}"#;
+    let ast = Ast::from_json(text).unwrap();
+    assert_eq!(ast.to_string(), "(if (< (arg 0) (imm 0)) (imm -1) (imm 1))");
+    assert_eq!(ast.to_json(), text);
+}
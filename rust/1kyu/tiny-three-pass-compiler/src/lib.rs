@@ -0,0 +1,2275 @@
+// https://www.codewars.com/kata/tiny-three-pass-compiler/train/rust
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::num::IntErrorKind;
+use std::iter::Peekable;
+use std::ops::Range;
+use std::str::CharIndices;
+
+mod c;
+mod closure;
+mod cse;
+mod dump;
+mod fold;
+mod fuzz;
+mod json;
+mod wat;
+mod x86;
+
+pub use dump::{interleave, three_address};
+pub use fold::{AstFold, PassManager, StrengthReduction};
+pub use fuzz::{fuzz, random_program, Failure, Outcome, Random};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    // comparisons evaluate to 1 when they hold and to 0 otherwise
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+}
+
+impl BinOp {
+    pub fn from_symbol(symbol: &str) -> Option<BinOp> {
+        match symbol {
+            "+" => Some(BinOp::Add),
+            "-" => Some(BinOp::Sub),
+            "*" => Some(BinOp::Mul),
+            "/" => Some(BinOp::Div),
+            "%" => Some(BinOp::Mod),
+            "<" => Some(BinOp::Lt),
+            "<=" => Some(BinOp::Le),
+            ">" => Some(BinOp::Gt),
+            ">=" => Some(BinOp::Ge),
+            "==" => Some(BinOp::Eq),
+            "!=" => Some(BinOp::Ne),
+            _ => None,
+        }
+    }
+
+    pub fn symbol(self) -> &'static str {
+        match self {
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+            BinOp::Mod => "%",
+            BinOp::Lt => "<",
+            BinOp::Le => "<=",
+            BinOp::Gt => ">",
+            BinOp::Ge => ">=",
+            BinOp::Eq => "==",
+            BinOp::Ne => "!=",
+        }
+    }
+
+    pub fn from_instruction(instruction: &str) -> Option<BinOp> {
+        match instruction {
+            "AD" => Some(BinOp::Add),
+            "SU" => Some(BinOp::Sub),
+            "MU" => Some(BinOp::Mul),
+            "DI" => Some(BinOp::Div),
+            "MO" => Some(BinOp::Mod),
+            "LT" => Some(BinOp::Lt),
+            "LE" => Some(BinOp::Le),
+            "GT" => Some(BinOp::Gt),
+            "GE" => Some(BinOp::Ge),
+            "EQ" => Some(BinOp::Eq),
+            "NE" => Some(BinOp::Ne),
+            _ => None,
+        }
+    }
+
+    pub fn instruction(self) -> &'static str {
+        match self {
+            BinOp::Add => "AD",
+            BinOp::Sub => "SU",
+            BinOp::Mul => "MU",
+            BinOp::Div => "DI",
+            BinOp::Mod => "MO",
+            BinOp::Lt => "LT",
+            BinOp::Le => "LE",
+            BinOp::Gt => "GT",
+            BinOp::Ge => "GE",
+            BinOp::Eq => "EQ",
+            BinOp::Ne => "NE",
+        }
+    }
+
+    // The target machine's arithmetic, which panics on division by zero.
+    pub fn evaluate(self, x: i64, y: i64) -> i64 {
+        match self {
+            BinOp::Add => x + y,
+            BinOp::Sub => x - y,
+            BinOp::Mul => x * y,
+            BinOp::Div => x / y,
+            BinOp::Mod => x % y,
+            BinOp::Lt => (x < y) as i64,
+            BinOp::Le => (x <= y) as i64,
+            BinOp::Gt => (x > y) as i64,
+            BinOp::Ge => (x >= y) as i64,
+            BinOp::Eq => (x == y) as i64,
+            BinOp::Ne => (x != y) as i64,
+        }
+    }
+
+    // The float mode's arithmetic, where `%` is the remainder of truncated division like
+    // C's fmod, and comparisons give 1 or 0.
+    pub fn evaluate_f64(self, x: f64, y: f64) -> f64 {
+        match self {
+            BinOp::Add => x + y,
+            BinOp::Sub => x - y,
+            BinOp::Mul => x * y,
+            BinOp::Div => x / y,
+            BinOp::Mod => x % y,
+            BinOp::Lt => (x < y) as i64 as f64,
+            BinOp::Le => (x <= y) as i64 as f64,
+            BinOp::Gt => (x > y) as i64 as f64,
+            BinOp::Ge => (x >= y) as i64 as f64,
+            BinOp::Eq => (x == y) as i64 as f64,
+            BinOp::Ne => (x != y) as i64 as f64,
+        }
+    }
+
+    pub fn is_commutative(self) -> bool {
+        matches!(self, BinOp::Add | BinOp::Mul | BinOp::Eq | BinOp::Ne)
+    }
+
+    pub fn is_associative(self) -> bool {
+        self == BinOp::Add || self == BinOp::Mul
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Leaf {
+    Imm,
+    Arg,
+    // A let-bound variable, numbered by how many `let`s enclose its binding.
+    Var,
+    // An f64 literal of the float mode, held as its bits so that trees stay Eq and Hash.
+    Float,
+}
+
+impl Leaf {
+    pub fn from_name(name: &str) -> Option<Leaf> {
+        match name {
+            "imm" => Some(Leaf::Imm),
+            "arg" => Some(Leaf::Arg),
+            "var" => Some(Leaf::Var),
+            "float" => Some(Leaf::Float),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Leaf::Imm => "imm",
+            Leaf::Arg => "arg",
+            Leaf::Var => "var",
+            Leaf::Float => "float",
+        }
+    }
+}
+
+// Where a node comes from: the byte offsets of the token that produced it, e.g. the operator
+// of a binary operation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    // 1-based line and column of the start of the span.
+    pub fn line_column(self, source: &str) -> (usize, usize) {
+        let before = &source[..self.start];
+        let line = before.matches('\n').count() + 1;
+        let column = before.rsplit('\n').next().unwrap().chars().count() + 1;
+        (line, column)
+    }
+
+    // The line of `source` that the span starts on, with the span underlined below it.
+    pub fn underline(self, source: &str) -> String {
+        let (_, column) = self.line_column(source);
+        let line_start = source[..self.start].rfind('\n').map_or(0, |i| i + 1);
+        let line = source[line_start..].lines().next().unwrap_or("");
+        let width = source[self.start..self.end].chars().count().max(1);
+        format!("{}\n{}{}", line, " ".repeat(column - 1), "^".repeat(width))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Ast {
+    BinOp(BinOp, Box<Ast>, Box<Ast>),
+    UnOp(Leaf, i64),
+    // `let var = value in body`
+    Let(Box<Ast>, Box<Ast>),
+    // `name(args...)`, a call to another function of the program
+    Call(String, Vec<Ast>),
+    // `if cond then a else b`, where any non-zero condition holds
+    If(Box<Ast>, Box<Ast>, Box<Ast>),
+    // the node inside comes from `span` of the source; trees compare equal regardless of spans
+    At(Span, Box<Ast>),
+}
+
+impl PartialEq for Ast {
+    fn eq(&self, other: &Ast) -> bool {
+        match (self.strip(), other.strip()) {
+            (Ast::BinOp(op, lhs, rhs), Ast::BinOp(op2, lhs2, rhs2)) => op == op2 && lhs == lhs2 && rhs == rhs2,
+            (Ast::UnOp(leaf, x), Ast::UnOp(leaf2, x2)) => leaf == leaf2 && x == x2,
+            (Ast::Let(value, body), Ast::Let(value2, body2)) => value == value2 && body == body2,
+            (Ast::Call(name, args), Ast::Call(name2, args2)) => name == name2 && args == args2,
+            (Ast::If(cond, then, other), Ast::If(cond2, then2, other2)) => cond == cond2 && then == then2 && other == other2,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Ast {}
+
+impl Hash for Ast {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let ast = self.strip();
+        std::mem::discriminant(ast).hash(state);
+        match ast {
+            Ast::BinOp(op, lhs, rhs) => (op, lhs, rhs).hash(state),
+            Ast::UnOp(leaf, x) => (leaf, x).hash(state),
+            Ast::Let(value, body) => (value, body).hash(state),
+            Ast::Call(name, args) => (name, args).hash(state),
+            Ast::If(cond, then, other) => (cond, then, other).hash(state),
+            Ast::At(..) => unreachable!(),
+        }
+    }
+}
+
+// The kata's own representation, where operators and node kinds are plain strings.
+// A `let` is tagged like a binary operator with the bound value on the left.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TaggedAst {
+    BinOp(String, Box<TaggedAst>, Box<TaggedAst>),
+    UnOp(String, i64),
+    Call(String, Vec<TaggedAst>),
+    If(Box<TaggedAst>, Box<TaggedAst>, Box<TaggedAst>),
+}
+
+impl From<&Ast> for TaggedAst {
+    fn from(ast: &Ast) -> TaggedAst {
+        match ast {
+            Ast::BinOp(op, lhs, rhs) => TaggedAst::BinOp(
+                op.symbol().to_string(),
+                Box::new(TaggedAst::from(&**lhs)),
+                Box::new(TaggedAst::from(&**rhs)),
+            ),
+            Ast::UnOp(leaf, x) => TaggedAst::UnOp(leaf.name().to_string(), *x),
+            Ast::Let(value, body) => TaggedAst::BinOp(
+                "let".to_string(),
+                Box::new(TaggedAst::from(&**value)),
+                Box::new(TaggedAst::from(&**body)),
+            ),
+            Ast::Call(name, args) => TaggedAst::Call(name.clone(), args.iter().map(TaggedAst::from).collect()),
+            Ast::If(cond, then, other) => TaggedAst::If(
+                Box::new(TaggedAst::from(&**cond)),
+                Box::new(TaggedAst::from(&**then)),
+                Box::new(TaggedAst::from(&**other)),
+            ),
+            Ast::At(_, ast) => TaggedAst::from(&**ast),
+        }
+    }
+}
+
+impl TryFrom<&TaggedAst> for Ast {
+    type Error = CompileError;
+
+    fn try_from(ast: &TaggedAst) -> Result<Ast, CompileError> {
+        match ast {
+            TaggedAst::BinOp(op, value, body) if op == "let" => {
+                Ok(Ast::let_in(Ast::try_from(&**value)?, Ast::try_from(&**body)?))
+            },
+            TaggedAst::BinOp(op, lhs, rhs) => match BinOp::from_symbol(op) {
+                Some(op) => Ok(Ast::bin(op, Ast::try_from(&**lhs)?, Ast::try_from(&**rhs)?)),
+                None => Err(CompileError::UnknownOperator(op.clone())),
+            },
+            TaggedAst::UnOp(leaf, x) => match Leaf::from_name(leaf) {
+                Some(leaf) => Ok(Ast::UnOp(leaf, *x)),
+                None => Err(CompileError::UnknownOperator(leaf.clone())),
+            },
+            TaggedAst::Call(name, args) => {
+                Ok(Ast::Call(name.clone(), args.iter().map(Ast::try_from).collect::<Result<_, _>>()?))
+            },
+            TaggedAst::If(cond, then, other) => {
+                Ok(Ast::cond(Ast::try_from(&**cond)?, Ast::try_from(&**then)?, Ast::try_from(&**other)?))
+            },
+        }
+    }
+}
+
+impl Ast {
+    pub fn imm(x: i64) -> Ast { Ast::UnOp(Leaf::Imm, x) }
+
+    pub fn arg(index: i64) -> Ast { Ast::UnOp(Leaf::Arg, index) }
+
+    pub fn var(level: i64) -> Ast { Ast::UnOp(Leaf::Var, level) }
+
+    pub fn bin(op: BinOp, lhs: Ast, rhs: Ast) -> Ast { Ast::BinOp(op, Box::new(lhs), Box::new(rhs) ) }
+
+    pub fn let_in(value: Ast, body: Ast) -> Ast { Ast::Let(Box::new(value), Box::new(body)) }
+
+    pub fn cond(cond: Ast, then: Ast, other: Ast) -> Ast { Ast::If(Box::new(cond), Box::new(then), Box::new(other)) }
+
+    pub fn at(span: Span, ast: Ast) -> Ast { Ast::At(span, Box::new(ast)) }
+
+    pub fn float(x: f64) -> Ast { Ast::UnOp(Leaf::Float, x.to_bits() as i64) }
+
+    // The node without the spans around it.
+    pub fn strip(&self) -> &Ast {
+        match self {
+            Ast::At(_, ast) => ast.strip(),
+            _ => self,
+        }
+    }
+
+    fn imm_value(&self) -> Option<i64> {
+        match self.strip() {
+            Ast::UnOp(Leaf::Imm, x) => Some(*x),
+            _ => None,
+        }
+    }
+
+    // The value of a constant in the float mode, where integer literals are exact floats too.
+    fn float_value(&self) -> Option<f64> {
+        match self.strip() {
+            Ast::UnOp(Leaf::Imm, x) => Some(*x as f64),
+            Ast::UnOp(Leaf::Float, bits) => Some(f64::from_bits(*bits as u64)),
+            _ => None,
+        }
+    }
+
+    // Whether evaluating the tree may divide by zero, in which case it cannot be discarded.
+    fn may_trap(&self) -> bool {
+        match self {
+            Ast::UnOp(..) => false,
+            Ast::BinOp(op, lhs, rhs) => {
+                let divides = *op == BinOp::Div || *op == BinOp::Mod;
+                (divides && rhs.imm_value().is_none_or(|y| y == 0)) || lhs.may_trap() || rhs.may_trap()
+            },
+            Ast::Let(value, body) => value.may_trap() || body.may_trap(),
+            // the callee might divide by zero, or never return
+            Ast::Call(..) => true,
+            Ast::If(cond, then, other) => cond.may_trap() || then.may_trap() || other.may_trap(),
+            Ast::At(_, ast) => ast.may_trap(),
+        }
+    }
+
+    // `-e` is parsed as `-0.0 - e` in the float mode, which pass3 emits as `NG`.
+    fn is_negative_zero(&self) -> bool {
+        matches!(self.strip(), Ast::UnOp(Leaf::Float, bits) if *bits == (-0.0f64).to_bits() as i64)
+    }
+
+    // `-e` is parsed as `0 - e`.
+    fn negated(&self) -> Option<&Ast> {
+        match self.strip() {
+            Ast::BinOp(BinOp::Sub, zero, e) if zero.imm_value() == Some(0) => Some(e),
+            _ => None,
+        }
+    }
+
+    fn count_uses(&self, level: i64) -> usize {
+        match self {
+            Ast::UnOp(Leaf::Var, x) => (*x == level) as usize,
+            Ast::UnOp(..) => 0,
+            Ast::BinOp(_, lhs, rhs) | Ast::Let(lhs, rhs) => lhs.count_uses(level) + rhs.count_uses(level),
+            Ast::Call(_, args) => args.iter().map(|arg| arg.count_uses(level)).sum(),
+            Ast::If(cond, then, other) => cond.count_uses(level) + then.count_uses(level) + other.count_uses(level),
+            Ast::At(_, ast) => ast.count_uses(level),
+        }
+    }
+
+    // Whether the variable bound at `level` is only evaluated when a branch is taken.
+    fn used_in_branch(&self, level: i64) -> bool {
+        match self {
+            Ast::UnOp(..) => false,
+            Ast::BinOp(_, lhs, rhs) | Ast::Let(lhs, rhs) => lhs.used_in_branch(level) || rhs.used_in_branch(level),
+            Ast::Call(_, args) => args.iter().any(|arg| arg.used_in_branch(level)),
+            Ast::If(cond, then, other) => {
+                cond.used_in_branch(level) || then.count_uses(level) > 0 || other.count_uses(level) > 0
+            },
+            Ast::At(_, ast) => ast.used_in_branch(level),
+        }
+    }
+
+    fn check_calls(&self, program: &Program) -> Result<(), String> {
+        match self {
+            Ast::UnOp(..) => Ok(()),
+            Ast::BinOp(_, lhs, rhs) | Ast::Let(lhs, rhs) => {
+                lhs.check_calls(program)?;
+                rhs.check_calls(program)
+            },
+            Ast::Call(name, args) => {
+                match program.function(name) {
+                    Some(f) if f.arity == args.len() => (),
+                    Some(f) => return Err(format!("{} takes {} arguments but {} were given", name, f.arity, args.len())),
+                    None => return Err(format!("undefined function {}", name)),
+                }
+                args.iter().try_for_each(|arg| arg.check_calls(program))
+            },
+            Ast::If(cond, then, other) => {
+                cond.check_calls(program)?;
+                then.check_calls(program)?;
+                other.check_calls(program)
+            },
+            Ast::At(_, ast) => ast.check_calls(program),
+        }
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            Ast::UnOp(..) => 1,
+            Ast::BinOp(_, lhs, rhs) | Ast::Let(lhs, rhs) => 1 + lhs.size() + rhs.size(),
+            Ast::Call(_, args) => 1 + args.iter().map(Ast::size).sum::<usize>(),
+            Ast::If(cond, then, other) => 1 + cond.size() + then.size() + other.size(),
+            Ast::At(_, ast) => ast.size(),
+        }
+    }
+
+    fn callees(&self, output: &mut Vec<String>) {
+        match self {
+            Ast::UnOp(..) => (),
+            Ast::BinOp(_, lhs, rhs) | Ast::Let(lhs, rhs) => {
+                lhs.callees(output);
+                rhs.callees(output);
+            },
+            Ast::Call(name, args) => {
+                output.push(name.clone());
+                args.iter().for_each(|arg| arg.callees(output));
+            },
+            Ast::If(cond, then, other) => {
+                cond.callees(output);
+                then.callees(output);
+                other.callees(output);
+            },
+            Ast::At(_, ast) => ast.callees(output),
+        }
+    }
+
+    fn map_leaves(&self, f: &dyn Fn(Leaf, i64) -> Ast) -> Ast {
+        match self {
+            Ast::UnOp(leaf, x) => f(*leaf, *x),
+            Ast::BinOp(op, lhs, rhs) => Ast::bin(*op, lhs.map_leaves(f), rhs.map_leaves(f)),
+            Ast::Let(value, body) => Ast::let_in(value.map_leaves(f), body.map_leaves(f)),
+            Ast::Call(name, args) => Ast::Call(name.clone(), args.iter().map(|arg| arg.map_leaves(f)).collect()),
+            Ast::If(cond, then, other) => Ast::cond(cond.map_leaves(f), then.map_leaves(f), other.map_leaves(f)),
+            Ast::At(span, ast) => Ast::at(*span, ast.map_leaves(f)),
+        }
+    }
+
+    // Renumbers the variables bound at `from` or deeper, for a tree moved under `by` more `let`s.
+    fn shift(&self, from: i64, by: i64) -> Ast {
+        self.map_leaves(&|leaf, x| match leaf {
+            Leaf::Var if x >= from => Ast::var(x + by),
+            _ => Ast::UnOp(leaf, x),
+        })
+    }
+
+    // Replaces calls of small functions that call nothing themselves by their bodies,
+    // with each argument bound by a `let` so that it is still evaluated exactly once.
+    fn inline_calls(&self, program: &Program, depth: i64) -> Ast {
+        match self {
+            Ast::UnOp(..) => self.clone(),
+            Ast::BinOp(op, lhs, rhs) => Ast::bin(*op, lhs.inline_calls(program, depth), rhs.inline_calls(program, depth)),
+            Ast::Let(value, body) => Ast::let_in(value.inline_calls(program, depth), body.inline_calls(program, depth + 1)),
+            Ast::Call(name, args) => {
+                let args: Vec<Ast> = args.iter().map(|arg| arg.inline_calls(program, depth)).collect();
+                let callee = match program.function(name) {
+                    Some(callee) if callee.is_inlinable() => callee,
+                    _ => return Ast::Call(name.clone(), args),
+                };
+                let arity = args.len() as i64;
+                let body = callee.body.map_leaves(&|leaf, x| match leaf {
+                    Leaf::Arg => Ast::var(depth + x),
+                    Leaf::Var => Ast::var(depth + arity + x),
+                    Leaf::Imm | Leaf::Float => Ast::UnOp(leaf, x),
+                });
+                args.iter().enumerate().rev().fold(body, |body, (i, arg)| Ast::let_in(arg.shift(depth, i as i64), body))
+            },
+            Ast::If(cond, then, other) => Ast::cond(
+                cond.inline_calls(program, depth),
+                then.inline_calls(program, depth),
+                other.inline_calls(program, depth),
+            ),
+            Ast::At(span, ast) => Ast::at(*span, ast.inline_calls(program, depth)),
+        }
+    }
+
+    // Replaces the variable bound at `level` with `value` and renumbers the variables bound
+    // inside it, as if the `let` at `level` had never been there.
+    fn substitute(&self, level: i64, value: &Ast) -> Ast {
+        self.substitute_at(level, value, level)
+    }
+
+    // `depth` is the level of a variable bound here once the `let` is gone. The `let`s inside
+    // `value` bind from `level` where it was written, and from `depth` where it is used.
+    fn substitute_at(&self, level: i64, value: &Ast, depth: i64) -> Ast {
+        match self {
+            Ast::UnOp(Leaf::Var, x) if *x == level => value.shift(level, depth - level),
+            Ast::UnOp(Leaf::Var, x) if *x > level => Ast::var(x - 1),
+            Ast::UnOp(..) => self.clone(),
+            Ast::BinOp(op, lhs, rhs) => Ast::bin(*op, lhs.substitute_at(level, value, depth), rhs.substitute_at(level, value, depth)),
+            Ast::Let(bound, body) => {
+                Ast::let_in(bound.substitute_at(level, value, depth), body.substitute_at(level, value, depth + 1))
+            },
+            Ast::Call(name, args) => {
+                Ast::Call(name.clone(), args.iter().map(|arg| arg.substitute_at(level, value, depth)).collect())
+            },
+            Ast::If(cond, then, other) => Ast::cond(
+                cond.substitute_at(level, value, depth),
+                then.substitute_at(level, value, depth),
+                other.substitute_at(level, value, depth),
+            ),
+            Ast::At(span, ast) => Ast::at(*span, ast.substitute_at(level, value, depth)),
+        }
+    }
+
+    // `depth` is the number of `let`s around this node, which is also the level of a variable bound here.
+    fn reduce(&self, depth: i64, arithmetic: Arithmetic) -> Result<Ast, CompileError> {
+        match self {
+            Ast::UnOp(..) => Ok(self.clone()),
+            Ast::BinOp(op, lhs, rhs) => {
+                Ast::simplify(*op, lhs.reduce(depth, arithmetic)?, rhs.reduce(depth, arithmetic)?, arithmetic)
+            },
+            Ast::Let(value, body) => {
+                let value = value.reduce(depth, arithmetic)?;
+                // leaves are as cheap as a stack slot, and a single use might fold with its surroundings,
+                // but a division by zero must not move into a branch that might not be taken
+                let movable = |body: &Ast| match body.count_uses(depth) {
+                    0 => !value.may_trap(),
+                    1 => !value.may_trap() || !body.used_in_branch(depth),
+                    _ => false,
+                };
+                if value.is_leaf() || movable(body) {
+                    return body.substitute(depth, &value).reduce(depth, arithmetic);
+                }
+                let body = body.reduce(depth + 1, arithmetic)?;
+                if movable(&body) {
+                    Ok(body.substitute(depth, &value))
+                } else {
+                    Ok(Ast::let_in(value, body))
+                }
+            },
+            Ast::Call(name, args) => {
+                let args = args.iter().map(|arg| arg.reduce(depth, arithmetic)).collect::<Result<_, _>>()?;
+                Ok(Ast::Call(name.clone(), args))
+            },
+            // a branch that is never taken is dropped before it could report a constant division by zero
+            Ast::If(cond, then, other) => {
+                let cond = cond.reduce(depth, arithmetic)?;
+                match cond.imm_value() {
+                    Some(0) => other.reduce(depth, arithmetic),
+                    Some(_) => then.reduce(depth, arithmetic),
+                    None => {
+                        let (then, other) = (then.reduce(depth, arithmetic)?, other.reduce(depth, arithmetic)?);
+                        if then == other && !cond.may_trap() {
+                            Ok(then)
+                        } else {
+                            Ok(Ast::cond(cond, then, other))
+                        }
+                    },
+                }
+            },
+            // a rewrite that ends in one of the operands keeps the operand's own span
+            Ast::At(span, ast) => match ast.reduce(depth, arithmetic)? {
+                ast @ Ast::At(..) => Ok(ast),
+                ast => Ok(Ast::at(*span, ast)),
+            },
+        }
+    }
+
+    // pass2 in the float mode, which only evaluates what is constant: identities such as
+    // x * 0 = 0, x + 0 = x or reassociation do not hold for NaN, infinities and -0. Nothing
+    // traps, so `let`s of leaves are always substituted and any branch may be dropped.
+    fn reduce_f64(&self, depth: i64) -> Ast {
+        match self {
+            Ast::UnOp(..) => self.clone(),
+            Ast::BinOp(op, lhs, rhs) => {
+                let (lhs, rhs) = (lhs.reduce_f64(depth), rhs.reduce_f64(depth));
+                match (lhs.float_value(), rhs.float_value()) {
+                    (Some(x), Some(y)) => Ast::float(op.evaluate_f64(x, y)),
+                    _ => Ast::bin(*op, lhs, rhs),
+                }
+            },
+            Ast::Let(value, body) => {
+                let value = value.reduce_f64(depth);
+                if value.is_leaf() {
+                    body.substitute(depth, &value).reduce_f64(depth)
+                } else {
+                    Ast::let_in(value, body.reduce_f64(depth + 1))
+                }
+            },
+            Ast::Call(name, args) => Ast::Call(name.clone(), args.iter().map(|arg| arg.reduce_f64(depth)).collect()),
+            // NaN is true, like any other value but 0
+            Ast::If(cond, then, other) => {
+                let cond = cond.reduce_f64(depth);
+                match cond.float_value().map(|x| x != 0.0) {
+                    Some(false) => other.reduce_f64(depth),
+                    Some(true) => then.reduce_f64(depth),
+                    None => Ast::cond(cond, then.reduce_f64(depth), other.reduce_f64(depth)),
+                }
+            },
+            Ast::At(span, ast) => match ast.reduce_f64(depth) {
+                ast @ Ast::At(..) => ast,
+                ast => Ast::at(*span, ast),
+            },
+        }
+    }
+
+    fn simplify(op: BinOp, lhs: Ast, rhs: Ast, arithmetic: Arithmetic) -> Result<Ast, CompileError> {
+        use BinOp::*;
+        // `e op c` or `c op e` where `op` is the same commutative operator
+        let constant_tail = |ast: &Ast| match ast.strip() {
+            Ast::BinOp(inner, e, c) if *inner == op && op.is_associative() && c.imm_value().is_some() => {
+                Some(((**e).clone(), (**c).clone()))
+            },
+            Ast::BinOp(inner, c, e) if *inner == op && op.is_associative() && c.imm_value().is_some() => {
+                Some(((**e).clone(), (**c).clone()))
+            },
+            _ => None,
+        };
+        Ok(match (op, lhs.imm_value(), rhs.imm_value()) {
+            (_, Some(x), Some(y)) => match arithmetic.apply(op, x, y) {
+                Ok(z) => Ast::imm(z),
+                Err(()) if y == 0 && (op == Div || op == Mod) => return Err(CompileError::DivisionByZero(Ast::bin(op, lhs, rhs).to_string())),
+                Err(()) => return Err(CompileError::Overflow(Ast::bin(op, lhs, rhs).to_string())),
+            },
+            (Add, Some(0), _) | (Mul, Some(1), _) => rhs,
+            (Add, _, Some(0)) | (Sub, _, Some(0)) | (Mul, _, Some(1)) | (Div, _, Some(1)) => lhs,
+            (Mul, Some(0), _) if !rhs.may_trap() => Ast::imm(0),
+            (Mul, _, Some(0)) if !lhs.may_trap() => Ast::imm(0),
+            (Sub, _, _) if lhs == rhs && !lhs.may_trap() => Ast::imm(0),
+            (Mod, _, Some(1)) | (Mod, _, Some(-1)) if !lhs.may_trap() => Ast::imm(0),
+            // -(-e) => e
+            (Sub, Some(0), None) if rhs.negated().is_some() => rhs.negated().unwrap().clone(),
+            // c1 + (e + c2) => (e + c2) + c1, which folds below; otherwise the operands keep their order
+            (_, Some(_), None) if op.is_commutative() && constant_tail(&rhs).is_some() => Ast::simplify(op, rhs, lhs, arithmetic)?,
+            // (e + c1) + c2 => e + (c1 + c2) for + and *, unless c1 + c2 alone would overflow
+            (_, None, Some(y)) if constant_tail(&lhs).is_some() => {
+                let (e, c) = constant_tail(&lhs).unwrap();
+                match Arithmetic::Checked.apply(op, c.imm_value().unwrap(), y) {
+                    Ok(z) => Ast::simplify(op, e, Ast::imm(z), arithmetic)?,
+                    Err(()) => Ast::bin(op, lhs, rhs),
+                }
+            },
+            _ => Ast::bin(op, lhs, rhs),
+        })
+    }
+
+    fn emit(&self, frame: &mut Frame, output: &mut Vec<String>) {
+        match self {
+            Ast::UnOp(Leaf::Imm, val) => output.push(format!("IM {}", val)),
+            Ast::UnOp(Leaf::Float, bits) => output.push(format!("IM {:?}", f64::from_bits(*bits as u64))),
+            Ast::UnOp(Leaf::Arg, val) => output.push(format!("AR {}", val)),
+            Ast::UnOp(Leaf::Var, level) => output.push(format!("LD {}", frame.slots[*level as usize])),
+            Ast::BinOp(BinOp::Sub, zero, e) if zero.is_negative_zero() => {
+                e.emit(frame, output);
+                output.push("NG".to_string());
+            },
+            Ast::BinOp(op, lhs, rhs) => {
+                lhs.emit(frame, output);
+                output.push("SW".to_string());
+                frame.push(output);
+                rhs.emit(frame, output);
+                output.push("SW".to_string());
+                output.push(op.instruction().to_string());
+                output.push("SW".to_string());
+                frame.pop(output);
+                output.push("SW".to_string());
+            },
+            Ast::Let(value, body) => {
+                value.emit(frame, output);
+                frame.bind(output);
+                body.emit(frame, output);
+                frame.unbind(output);
+            },
+            Ast::Call(name, args) => {
+                for arg in args {
+                    arg.emit(frame, output);
+                    frame.push(output);
+                }
+                frame.call(name, args.len(), output);
+            },
+            Ast::If(cond, then, other) => {
+                let (other_label, end_label) = (frame.label(), frame.label());
+                cond.emit(frame, output);
+                output.push(format!("JZ {}", other_label));
+                then.emit(frame, output);
+                output.push(format!("JP {}", end_label));
+                output.push(format!("LB {}", other_label));
+                other.emit(frame, output);
+                output.push(format!("LB {}", end_label));
+            },
+            Ast::At(span, ast) => {
+                let start = output.len();
+                ast.emit(frame, output);
+                frame.sources.push((start..output.len(), *span));
+            },
+        }
+    }
+
+    // `vars` holds the values of the let-bound variables in scope, indexed by level.
+    fn eval<T: Word>(&self, program: &Program, args: &[T], vars: &mut Vec<T>) -> T {
+        match self {
+            Ast::UnOp(Leaf::Imm, x) => T::from_imm(*x),
+            Ast::UnOp(Leaf::Float, bits) => T::from_float(f64::from_bits(*bits as u64)),
+            Ast::UnOp(Leaf::Arg, index) => args[*index as usize],
+            Ast::UnOp(Leaf::Var, level) => vars[*level as usize],
+            Ast::BinOp(op, lhs, rhs) => {
+                let x = lhs.eval(program, args, vars);
+                T::apply(*op, x, rhs.eval(program, args, vars)).unwrap_or_else(|trap| panic!("{}", trap.message()))
+            },
+            Ast::Let(value, body) => {
+                let x = value.eval(program, args, vars);
+                vars.push(x);
+                let result = body.eval(program, args, vars);
+                vars.pop();
+                result
+            },
+            Ast::Call(name, call_args) => {
+                let values: Vec<T> = call_args.iter().map(|arg| arg.eval(program, args, vars)).collect();
+                program.function(name).unwrap().body.eval(program, &values, &mut Vec::new())
+            },
+            Ast::If(cond, then, other) => {
+                if !cond.eval(program, args, vars).is_zero() {
+                    then.eval(program, args, vars)
+                } else {
+                    other.eval(program, args, vars)
+                }
+            },
+            Ast::At(_, ast) => ast.eval(program, args, vars),
+        }
+    }
+
+    fn is_leaf(&self) -> bool {
+        matches!(self.strip(), Ast::UnOp(..))
+    }
+
+    // Ershov number: how many registers are needed to evaluate the tree without spilling.
+    fn need(&self) -> usize {
+        match self {
+            Ast::UnOp(..) => 1,
+            Ast::BinOp(BinOp::Sub, zero, e) if zero.is_negative_zero() => e.need(),
+            Ast::BinOp(_, lhs, rhs) => {
+                let (l, r) = (lhs.need(), rhs.need());
+                if l == r { l + 1 } else { l.max(r) }
+            },
+            Ast::Let(value, body) => value.need().max(body.need()),
+            // the callee may use both registers
+            Ast::Call(_, args) => args.iter().map(Ast::need).fold(2, usize::max),
+            Ast::If(cond, then, other) => cond.need().max(then.need()).max(other.need()),
+            Ast::At(_, ast) => ast.need(),
+        }
+    }
+
+    // Sethi-Ullman code generation. The result ends up in R0, R1 is clobbered.
+    // Only spills to the stack when both operands need both registers.
+    fn emit_ordered(&self, frame: &mut Frame, output: &mut Vec<String>) {
+        match self {
+            Ast::UnOp(..) => self.emit(frame, output),
+            Ast::BinOp(BinOp::Sub, zero, e) if zero.is_negative_zero() => {
+                e.emit_ordered(frame, output);
+                output.push("NG".to_string());
+            },
+            Ast::BinOp(op, lhs, rhs) => {
+                if lhs.is_leaf() {
+                    // R1 = rhs, R0 = lhs
+                    rhs.emit_ordered(frame, output);
+                    output.push("SW".to_string());
+                    lhs.emit(frame, output);
+                } else if rhs.is_leaf() {
+                    // R1 = lhs, R0 = rhs
+                    lhs.emit_ordered(frame, output);
+                    output.push("SW".to_string());
+                    rhs.emit(frame, output);
+                    if !op.is_commutative() {
+                        output.push("SW".to_string());
+                    }
+                } else if lhs.need() >= rhs.need() {
+                    lhs.emit_ordered(frame, output);
+                    frame.push(output);
+                    rhs.emit_ordered(frame, output);
+                    output.push("SW".to_string());
+                    frame.pop(output);
+                } else {
+                    rhs.emit_ordered(frame, output);
+                    frame.push(output);
+                    lhs.emit_ordered(frame, output);
+                    output.push("SW".to_string());
+                    frame.pop(output);
+                    if !op.is_commutative() {
+                        output.push("SW".to_string());
+                    }
+                }
+                output.push(op.instruction().to_string());
+            },
+            Ast::Let(value, body) => {
+                value.emit_ordered(frame, output);
+                frame.bind(output);
+                body.emit_ordered(frame, output);
+                frame.unbind(output);
+            },
+            Ast::Call(name, args) => {
+                for arg in args {
+                    arg.emit_ordered(frame, output);
+                    frame.push(output);
+                }
+                frame.call(name, args.len(), output);
+            },
+            Ast::If(cond, then, other) => {
+                let (other_label, end_label) = (frame.label(), frame.label());
+                cond.emit_ordered(frame, output);
+                output.push(format!("JZ {}", other_label));
+                then.emit_ordered(frame, output);
+                output.push(format!("JP {}", end_label));
+                output.push(format!("LB {}", other_label));
+                other.emit_ordered(frame, output);
+                output.push(format!("LB {}", end_label));
+            },
+            Ast::At(span, ast) => {
+                let start = output.len();
+                ast.emit_ordered(frame, output);
+                frame.sources.push((start..output.len(), *span));
+            },
+        }
+    }
+}
+
+// Where the values that pass3 keeps on the stack live.
+#[derive(Default)]
+struct Frame {
+    depth: usize,
+    // stack slot of each let-bound variable, indexed by its level
+    slots: Vec<usize>,
+    // prefix of the jump labels, which keeps the labels of different functions apart
+    function: String,
+    labels: usize,
+    // the instructions emitted for each node with a span, innermost nodes first
+    sources: Vec<(Range<usize>, Span)>,
+}
+
+impl Frame {
+    fn push(&mut self, output: &mut Vec<String>) {
+        output.push("PU".to_string());
+        self.depth += 1;
+    }
+
+    fn pop(&mut self, output: &mut Vec<String>) {
+        output.push("PO".to_string());
+        self.depth -= 1;
+    }
+
+    // Keeps R0 in a new stack slot for the variable of the innermost `let`.
+    fn bind(&mut self, output: &mut Vec<String>) {
+        self.slots.push(self.depth);
+        self.push(output);
+    }
+
+    // Drops the slot of the innermost variable, which is on top of the stack, and keeps R0.
+    fn unbind(&mut self, output: &mut Vec<String>) {
+        let slot = self.slots.pop().unwrap();
+        output.push(format!("ST {}", slot));
+        self.pop(output);
+    }
+
+    // A fresh jump target, defined by `LB label` until `layout` resolves it. Function names
+    // cannot contain `.`, so these never clash with them.
+    fn label(&mut self) -> String {
+        self.labels += 1;
+        format!("{}.L{}", self.function, self.labels - 1)
+    }
+
+    // The arguments on top of the stack become the callee's argument frame. The callee is
+    // referred to by name until `layout` resolves it.
+    fn call(&mut self, name: &str, argc: usize, output: &mut Vec<String>) {
+        output.push(format!("CA {} {}", name, argc));
+        self.depth -= argc;
+    }
+}
+
+impl fmt::Display for Ast {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Ast::UnOp(Leaf::Float, bits) => write!(f, "(float {:?})", f64::from_bits(*bits as u64)),
+            Ast::UnOp(leaf, x) => write!(f, "({} {})", leaf.name(), x),
+            Ast::BinOp(op, lhs, rhs) => write!(f, "({} {} {})", op.symbol(), lhs, rhs),
+            Ast::Let(value, body) => write!(f, "(let {} {})", value, body),
+            Ast::Call(name, args) => {
+                write!(f, "(call {}", name)?;
+                for arg in args {
+                    write!(f, " {}", arg)?;
+                }
+                write!(f, ")")
+            },
+            Ast::If(cond, then, other) => write!(f, "(if {} {} {})", cond, then, other),
+            Ast::At(_, ast) => write!(f, "{}", ast),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Function {
+    pub name: String,
+    pub arity: usize,
+    pub body: Ast,
+}
+
+impl Function {
+    // Only leaf functions are inlined, which also rules out recursion.
+    fn is_inlinable(&self) -> bool {
+        const INLINE_LIMIT: usize = 16;
+        let mut callees = Vec::new();
+        self.body.callees(&mut callees);
+        callees.is_empty() && self.body.size() <= INLINE_LIMIT
+    }
+}
+
+// A program made of `def name [ args ] expression` definitions, run from `main`.
+// A kata program `[ args ] expression` is a program with only `main`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Program {
+    pub functions: Vec<Function>,
+}
+
+impl Program {
+    pub fn function(&self, name: &str) -> Option<&Function> {
+        self.functions.iter().find(|f| f.name == name)
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if self.function("main").is_none() {
+            return Err("program has no main function".to_string());
+        }
+        for (i, f) in self.functions.iter().enumerate() {
+            if self.functions[..i].iter().any(|g| g.name == f.name) {
+                return Err(format!("function {} is defined twice", f.name));
+            }
+            f.body.check_calls(self)?;
+        }
+        Ok(())
+    }
+
+    // Runs `main` by walking its tree, which is what every backend has to agree with.
+    pub fn eval(&self, args: &[i64]) -> i64 {
+        self.function("main").unwrap().body.eval(self, args, &mut Vec::new())
+    }
+
+    // `eval` for the float mode.
+    pub fn eval_f64(&self, args: &[f64]) -> f64 {
+        self.function("main").unwrap().body.eval(self, args, &mut Vec::new())
+    }
+
+    // Drops the functions that `main` can no longer reach.
+    fn remove_unused(&mut self) {
+        let mut reachable = vec!["main".to_string()];
+        let mut i = 0;
+        while i < reachable.len() {
+            let mut callees = Vec::new();
+            self.function(&reachable[i]).unwrap().body.callees(&mut callees);
+            for callee in callees {
+                if !reachable.contains(&callee) {
+                    reachable.push(callee);
+                }
+            }
+            i += 1;
+        }
+        self.functions.retain(|f| reachable.contains(&f.name));
+    }
+}
+
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for function in &self.functions {
+            writeln!(f, "(def {} {} {})", function.name, function.arity, function.body)?;
+        }
+        Ok(())
+    }
+}
+
+// How pass2 treats constant expressions whose result does not fit in an i64.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Arithmetic {
+    Checked,
+    Wrapping,
+    Saturating,
+}
+
+impl Arithmetic {
+    // Division by zero is an error under every policy.
+    fn apply(self, op: BinOp, x: i64, y: i64) -> Result<i64, ()> {
+        let result = match (self, op) {
+            (_, BinOp::Div) | (_, BinOp::Mod) if y == 0 => None,
+            (_, BinOp::Lt) => Some((x < y) as i64),
+            (_, BinOp::Le) => Some((x <= y) as i64),
+            (_, BinOp::Gt) => Some((x > y) as i64),
+            (_, BinOp::Ge) => Some((x >= y) as i64),
+            (_, BinOp::Eq) => Some((x == y) as i64),
+            (_, BinOp::Ne) => Some((x != y) as i64),
+            (Arithmetic::Checked, BinOp::Add) => x.checked_add(y),
+            (Arithmetic::Checked, BinOp::Sub) => x.checked_sub(y),
+            (Arithmetic::Checked, BinOp::Mul) => x.checked_mul(y),
+            (Arithmetic::Checked, BinOp::Div) => x.checked_div(y),
+            (Arithmetic::Checked, BinOp::Mod) => x.checked_rem(y),
+            (Arithmetic::Wrapping, BinOp::Add) => Some(x.wrapping_add(y)),
+            (Arithmetic::Wrapping, BinOp::Sub) => Some(x.wrapping_sub(y)),
+            (Arithmetic::Wrapping, BinOp::Mul) => Some(x.wrapping_mul(y)),
+            (Arithmetic::Wrapping, BinOp::Div) => Some(x.wrapping_div(y)),
+            (Arithmetic::Wrapping, BinOp::Mod) => Some(x.wrapping_rem(y)),
+            (Arithmetic::Saturating, BinOp::Add) => Some(x.saturating_add(y)),
+            (Arithmetic::Saturating, BinOp::Sub) => Some(x.saturating_sub(y)),
+            (Arithmetic::Saturating, BinOp::Mul) => Some(x.saturating_mul(y)),
+            (Arithmetic::Saturating, BinOp::Div) => Some(x.saturating_div(y)),
+            // the only overflowing case is i64::MIN % -1, which is 0 in exact arithmetic
+            (Arithmetic::Saturating, BinOp::Mod) => Some(x.wrapping_rem(y)),
+        };
+        result.ok_or(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum CompileError {
+    DivisionByZero(String),
+    Overflow(String),
+    UnknownOperator(String),
+    InvalidJson(String),
+    InvalidPass(String),
+    Lex(LexError),
+    Syntax { message: String, span: Span },
+    // a call of an undefined function or with the wrong number of arguments, or a missing `main`
+    InvalidProgram(String),
+}
+
+impl CompileError {
+    // Where in the source the error is, for the errors of pass1.
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            CompileError::Lex(e) => Some(e.span()),
+            CompileError::Syntax { span, .. } => Some(*span),
+            _ => None,
+        }
+    }
+
+    // The message with the position of the error in `source`, if it has one.
+    pub fn describe(&self, source: &str) -> String {
+        match self.span() {
+            Some(span) => {
+                let (line, column) = span.line_column(source);
+                format!("{} at line {}, column {}", self, line, column)
+            },
+            None => self.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompileError::DivisionByZero(expr) => write!(f, "division by zero in constant expression {}", expr),
+            CompileError::Overflow(expr) => write!(f, "arithmetic overflow in constant expression {}", expr),
+            CompileError::UnknownOperator(op) => write!(f, "unknown operator {:?}", op),
+            CompileError::InvalidJson(message) => write!(f, "invalid JSON AST: {}", message),
+            CompileError::InvalidPass(message) => write!(f, "invalid pass: {}", message),
+            CompileError::Lex(e) => write!(f, "{}", e),
+            CompileError::Syntax { message, .. } => write!(f, "{}", message),
+            CompileError::InvalidProgram(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum LexError {
+    UnknownCharacter { character: char, span: Span },
+    // a literal above i64::MAX
+    Overflow { literal: String, span: Span },
+    // digits followed by letters, or `0x` without hexadecimal digits
+    InvalidLiteral { literal: String, span: Span },
+}
+
+impl LexError {
+    pub fn span(&self) -> Span {
+        match self {
+            LexError::UnknownCharacter { span, .. } | LexError::Overflow { span, .. } | LexError::InvalidLiteral { span, .. } => *span,
+        }
+    }
+
+    // The message with the position of the error in `source`.
+    pub fn describe(&self, source: &str) -> String {
+        let (line, column) = self.span().line_column(source);
+        format!("{} at line {}, column {}", self, line, column)
+    }
+}
+
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LexError::UnknownCharacter { character, .. } => write!(f, "unknown character {:?}", character),
+            LexError::Overflow { literal, .. } => write!(f, "literal {} does not fit in 64 bits", literal),
+            LexError::InvalidLiteral { literal, .. } => write!(f, "invalid literal {}", literal),
+        }
+    }
+}
+
+enum Token {
+    Identifier(String),
+    Literal(i64),
+    Decimal(f64),
+    Symbol(char),
+    // a comparison operator, which may be two characters long
+    Comparison(String),
+}
+
+// The parser's view of the tokens: a peekable iterator that also knows the span of the
+// token it returned last.
+struct Tokens<'a> {
+    tokens: &'a [(Token, Span)],
+    position: usize,
+    // whether `next` has run past the last token
+    exhausted: bool,
+}
+
+impl<'a> Tokens<'a> {
+    fn peek(&self) -> Option<&'a Token> {
+        self.tokens.get(self.position).map(|(token, _)| token)
+    }
+
+    fn span(&self) -> Span {
+        self.tokens[self.position - 1].1
+    }
+
+    // A syntax error at the token returned last, or just after the last token at the end.
+    fn error(&self, message: &str) -> CompileError {
+        let end = self.tokens.last().map_or(0, |(_, span)| span.end);
+        match self.position {
+            _ if self.exhausted => CompileError::Syntax { message: format!("{} before the end of the input", message), span: Span { start: end, end } },
+            0 => CompileError::Syntax { message: message.to_string(), span: Span { start: 0, end: 0 } },
+            _ => CompileError::Syntax { message: message.to_string(), span: self.span() },
+        }
+    }
+}
+
+impl<'a> Iterator for Tokens<'a> {
+    type Item = &'a Token;
+
+    fn next(&mut self) -> Option<&'a Token> {
+        let token = self.peek();
+        if token.is_some() {
+            self.position += 1;
+        } else {
+            self.exhausted = true;
+        }
+        token
+    }
+}
+
+const KEYWORDS: [&str; 5] = ["let", "in", "if", "then", "else"];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Codegen {
+    // The kata's reference emitter: every operator saves R1 on the stack.
+    Naive,
+    // Evaluates the operand that needs more registers first, see `Ast::emit_ordered`.
+    SethiUllman,
+}
+
+// Every function leaves the stack as it found it, so the depth is measured within one call.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CodeStats {
+    pub instructions: usize,
+    pub max_stack_depth: usize,
+}
+
+impl CodeStats {
+    pub fn of(asm: &[String]) -> CodeStats {
+        let mut depth = 0usize;
+        let mut max_stack_depth = 0usize;
+        for instr in asm {
+            match &instr[..2] {
+                "PU" => {
+                    depth += 1;
+                    max_stack_depth = max_stack_depth.max(depth);
+                },
+                "PO" => depth -= 1,
+                "CA" => depth -= instr.rsplit(' ').next().unwrap().parse::<usize>().unwrap(),
+                _ => (),
+            }
+        }
+        CodeStats { instructions: asm.len(), max_stack_depth }
+    }
+}
+
+impl fmt::Display for CodeStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} instructions, max stack depth {}", self.instructions, self.max_stack_depth)
+    }
+}
+
+// A compiled program that takes its arguments and returns its result.
+pub type Closure = Box<dyn Fn(&[i64]) -> i64>;
+
+pub struct Compiler {
+    args: HashMap<String, i32>,
+    // names of the let-bound variables in scope, indexed by level
+    locals: Vec<String>,
+    pub codegen: Codegen,
+    pub arithmetic: Arithmetic,
+    // whether pass2 inlines calls of small functions
+    pub inline: bool,
+    // whether pass2 computes repeated subexpressions only once
+    pub cse: bool,
+    // whether literals may be decimals and arithmetic is f64
+    pub float: bool,
+    // user-defined passes run at the end of pass2, on a lone tree as well as on a program
+    pub passes: PassManager,
+}
+
+impl Default for Compiler {
+    fn default() -> Compiler {
+        Compiler::new()
+    }
+}
+
+impl Compiler {
+    pub fn new() -> Compiler {
+        Compiler { args: HashMap::new(), locals: Vec::new(), codegen: Codegen::Naive, arithmetic: Arithmetic::Checked, inline: false, cse: false, float: false, passes: PassManager::new() }
+    }
+
+    pub fn tokenize(&self, program: &str) -> Vec<String> {
+        self.try_tokenize(program).unwrap_or_else(|e| panic!("{}", e.describe(program)))
+    }
+
+    pub fn try_tokenize(&self, program: &str) -> Result<Vec<String>, LexError> {
+        Ok(self.tokenize_(program)?.iter().map(|(x, _)| match x {
+            Token::Literal(x) => x.to_string(),
+            Token::Decimal(x) => x.to_string(),
+            Token::Identifier(x) => x.clone(),
+            Token::Symbol(x) => x.to_string(),
+            Token::Comparison(x) => x.clone(),
+        }).collect())
+    }
+
+    // The longest run of letters, digits and underscores.
+    fn word(iter: &mut Peekable<CharIndices>) -> String {
+        let mut tmp = String::new();
+        while let Some(&(_, c)) = iter.peek().filter(|&&(_, c)| c.is_ascii_alphanumeric() || c == '_') {
+            tmp.push(c);
+            iter.next();
+        }
+        tmp
+    }
+
+    fn tokenize_(&self, program : &str) -> Result<Vec<(Token, Span)>, LexError> {
+        let mut tokens = vec![];
+
+        let mut iter = program.char_indices().peekable();
+        while let Some(&(start, c)) = iter.peek() {
+            let token = match c {
+                'a'..='z' | 'A'..='Z' | '_' => Token::Identifier(Compiler::word(&mut iter)),
+                // letters are part of the literal so that `12ab` is an error rather than `12 ab`
+                '0'..='9' => {
+                    let mut tmp = Compiler::word(&mut iter);
+                    let decimal = iter.peek().map(|&(_, c)| c) == Some('.');
+                    if decimal {
+                        iter.next();
+                        tmp.push('.');
+                        tmp.push_str(&Compiler::word(&mut iter));
+                    }
+                    let span = Span { start, end: start + tmp.len() };
+                    if decimal {
+                        let digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+                        let valid = tmp.split('.').all(digits);
+                        match tmp.parse::<f64>() {
+                            Ok(x) if valid && x.is_finite() => Token::Decimal(x),
+                            Ok(_) if valid => return Err(LexError::Overflow { literal: tmp, span }),
+                            _ => return Err(LexError::InvalidLiteral { literal: tmp, span }),
+                        }
+                    } else {
+                        let (digits, radix) = match tmp.strip_prefix("0x").or_else(|| tmp.strip_prefix("0X")) {
+                            Some(digits) => (digits, 16),
+                            None => (tmp.as_str(), 10),
+                        };
+                        match i64::from_str_radix(digits, radix) {
+                            Ok(x) => Token::Literal(x),
+                            Err(e) if *e.kind() == IntErrorKind::PosOverflow => {
+                                return Err(LexError::Overflow { literal: tmp, span });
+                            },
+                            _ => return Err(LexError::InvalidLiteral { literal: tmp, span }),
+                        }
+                    }
+                },
+                '<' | '>' | '=' | '!' => {
+                    let mut tmp = iter.next().unwrap().1.to_string();
+                    if iter.peek().map(|&(_, c)| c) == Some('=') {
+                        tmp.push(iter.next().unwrap().1);
+                    }
+                    match tmp.as_str() {
+                        "=" => Token::Symbol('='),
+                        "!" => return Err(LexError::UnknownCharacter { character: '!', span: Span { start, end: start + 1 } }),
+                        _ => Token::Comparison(tmp),
+                    }
+                },
+                '[' | ']' | '(' | ')' | '+' | '-' | '*' | '/' | '%' | ',' | ';' => Token::Symbol(iter.next().unwrap().1),
+                c if c.is_whitespace() => {
+                    iter.next();
+                    continue;
+                },
+                c => return Err(LexError::UnknownCharacter { character: c, span: Span { start, end: start + c.len_utf8() } }),
+            };
+            let end = iter.peek().map_or(program.len(), |&(i, _)| i);
+            tokens.push((token, Span { start, end }));
+        }
+
+        Ok(tokens)
+    }
+
+    pub fn compile(&mut self, program : &str) -> Vec<String> {
+        self.try_compile(program).unwrap_or_else(|e| panic!("{}", e.describe(program)))
+    }
+
+    pub fn try_compile(&mut self, program : &str) -> Result<Vec<String>, CompileError> {
+        let program = self.try_pass1_program(program)?;
+        let program = self.pass2_program(&program)?;
+        Ok(self.pass3_program(&program))
+    }
+
+    fn parse_function(&mut self, iter: &mut Tokens) -> Result<Ast, CompileError> {
+        self.args.clear();
+        self.locals.clear();
+        let mut arg_counter = 0;
+        Compiler::expect_symbol(iter, '[')?;
+        while let Some(Token::Identifier(name)) = iter.peek() {
+            iter.next();
+            self.args.insert(name.clone(), arg_counter);
+            arg_counter += 1;
+        }
+        Compiler::expect_symbol(iter, ']')?;
+        self.parse_expression(iter)
+    }
+
+    // Comparisons bind looser than arithmetic and do not chain.
+    fn parse_expression(&mut self, iter: &mut Tokens) -> Result<Ast, CompileError> {
+        let lhs = self.parse_sum(iter)?;
+        match iter.peek() {
+            Some(Token::Comparison(op)) => {
+                iter.next();
+                let span = iter.span();
+                let rhs = self.parse_sum(iter)?;
+                if let Some(Token::Comparison(_)) = iter.peek() {
+                    iter.next();
+                    return Err(iter.error("comparisons cannot be chained"));
+                }
+                Ok(Ast::at(span, Ast::bin(BinOp::from_symbol(op).unwrap(), lhs, rhs)))
+            },
+            _ => Ok(lhs),
+        }
+    }
+
+    fn parse_sum(&mut self, iter: &mut Tokens) -> Result<Ast, CompileError> {
+        let mut lhs = self.parse_term(iter)?;
+        while let Some(Token::Symbol(c)) = iter.peek() {
+            if *c == '+' || *c == '-' {
+                iter.next();
+                let span = iter.span();
+                let rhs = self.parse_term(iter)?;
+                lhs = Ast::at(span, Ast::bin(BinOp::from_symbol(&c.to_string()).unwrap(), lhs, rhs));
+            } else {
+                break;
+            }
+        }
+        Ok(lhs)
+    }
+
+    fn parse_term(&mut self, iter: &mut Tokens) -> Result<Ast, CompileError> {
+        let mut lhs = self.parse_factor(iter)?;
+        while let Some(Token::Symbol(c)) = iter.peek() {
+            if *c == '*' || *c == '/' || *c == '%' {
+                iter.next();
+                let span = iter.span();
+                let rhs = self.parse_factor(iter)?;
+                lhs = Ast::at(span, Ast::bin(BinOp::from_symbol(&c.to_string()).unwrap(), lhs, rhs));
+            } else {
+                break;
+            }
+        }
+        Ok(lhs)
+    }
+
+    // Every literal is an f64 in the float mode, and decimals exist only there.
+    fn number(&self, iter: &Tokens, token: &Token, negative: bool) -> Result<Ast, CompileError> {
+        let sign = if negative { -1 } else { 1 };
+        match token {
+            Token::Literal(x) if self.float => Ok(Ast::float((sign * x) as f64)),
+            Token::Literal(x) => Ok(Ast::imm(sign * x)),
+            Token::Decimal(x) if self.float => Ok(Ast::float(sign as f64 * x)),
+            Token::Decimal(x) => Err(iter.error(&format!("decimal literal {} needs the float mode", x))),
+            _ => unreachable!(),
+        }
+    }
+
+    fn parse_factor(&mut self, iter: &mut Tokens) -> Result<Ast, CompileError> {
+        Ok(match iter.next() {
+            Some(token @ Token::Literal(_)) | Some(token @ Token::Decimal(_)) => Ast::at(iter.span(), self.number(iter, token, false)?),
+            Some(Token::Symbol('-')) => {
+                let minus = iter.span();
+                match iter.peek() {
+                    Some(token @ Token::Literal(_)) | Some(token @ Token::Decimal(_)) => {
+                        iter.next();
+                        Ast::at(Span { start: minus.start, end: iter.span().end }, self.number(iter, token, true)?)
+                    },
+                    // 0.0 - 0.0 is 0.0 where -0.0 - 0.0 is -0.0, as negation should be
+                    _ if self.float => Ast::at(minus, Ast::bin(BinOp::Sub, Ast::float(-0.0), self.parse_factor(iter)?)),
+                    _ => Ast::at(minus, Ast::bin(BinOp::Sub, Ast::imm(0), self.parse_factor(iter)?)),
+                }
+            },
+            Some(Token::Identifier(name)) if name == "let" => {
+                let span = iter.span();
+                let name = match iter.next() {
+                    Some(Token::Identifier(name)) if !KEYWORDS.contains(&name.as_str()) => name.clone(),
+                    _ => return Err(iter.error("expect a variable name after let")),
+                };
+                Compiler::expect_symbol(iter, '=')?;
+                let value = self.parse_expression(iter)?;
+                Compiler::expect_keyword(iter, "in")?;
+                self.locals.push(name);
+                let body = self.parse_expression(iter);
+                self.locals.pop();
+                Ast::at(span, Ast::let_in(value, body?))
+            },
+            Some(Token::Identifier(keyword)) if keyword == "if" => {
+                let span = iter.span();
+                let cond = self.parse_expression(iter)?;
+                Compiler::expect_keyword(iter, "then")?;
+                let then = self.parse_expression(iter)?;
+                Compiler::expect_keyword(iter, "else")?;
+                let other = self.parse_expression(iter)?;
+                Ast::at(span, Ast::cond(cond, then, other))
+            },
+            Some(Token::Identifier(name)) if matches!(iter.peek(), Some(Token::Symbol('('))) => {
+                let span = iter.span();
+                iter.next();
+                let mut args = Vec::new();
+                if let Some(Token::Symbol(')')) = iter.peek() {
+                    iter.next();
+                } else {
+                    loop {
+                        args.push(self.parse_expression(iter)?);
+                        match iter.next() {
+                            Some(Token::Symbol(',')) => continue,
+                            Some(Token::Symbol(')')) => break,
+                            _ => return Err(iter.error(&format!("expect , or ) in the arguments of {}", name))),
+                        }
+                    }
+                }
+                Ast::at(span, Ast::Call(name.clone(), args))
+            },
+            Some(Token::Identifier(name)) => Ast::at(iter.span(), match self.locals.iter().rposition(|local| local == name) {
+                Some(level) => Ast::var(level as i64),
+                None => match self.args.get(name.as_str()) {
+                    Some(index) => Ast::arg(*index as i64),
+                    None => return Err(iter.error(&format!("undeclared identifier {}", name))),
+                },
+            }),
+            Some(Token::Symbol('(')) => {
+                let content = self.parse_expression(iter)?;
+                Compiler::expect_symbol(iter, ')')?;
+                content
+            },
+            _ => return Err(iter.error("expect a number, variable or parenthesised expression")),
+        })
+    }
+
+    fn expect_symbol(iter: &mut Tokens, s: char) -> Result<(), CompileError> {
+        match iter.next() {
+            Some(Token::Symbol(c)) if *c == s => Ok(()),
+            _ => Err(iter.error(&format!("expect {}", s))),
+        }
+    }
+
+    fn expect_keyword(iter: &mut Tokens, keyword: &str) -> Result<(), CompileError> {
+        match iter.next() {
+            Some(Token::Identifier(name)) if name == keyword => Ok(()),
+            _ => Err(iter.error(&format!("expect {}", keyword))),
+        }
+    }
+
+    pub fn pass1(&mut self, program : &str) -> Ast {
+        let tokens = self.tokenize_(program).unwrap_or_else(|e| panic!("{}", e.describe(program)));
+        let mut iter = Tokens { tokens: &tokens, position: 0, exhausted: false };
+        self.parse_function(&mut iter).unwrap_or_else(|e| panic!("{}", e.describe(program)))
+    }
+
+    pub fn pass1_program(&mut self, program : &str) -> Program {
+        self.try_pass1_program(program).unwrap_or_else(|e| panic!("{}", e.describe(program)))
+    }
+
+    // Parses either a kata program or a sequence of `def name [ args ] expression`
+    // separated by semicolons.
+    pub fn try_pass1_program(&mut self, program : &str) -> Result<Program, CompileError> {
+        let tokens = self.tokenize_(program).map_err(CompileError::Lex)?;
+        let mut iter = Tokens { tokens: &tokens, position: 0, exhausted: false };
+        let mut functions = Vec::new();
+        if let Some(Token::Identifier(keyword)) = iter.peek() {
+            if keyword == "def" {
+                while iter.peek().is_some() {
+                    Self::expect_keyword(&mut iter, "def")?;
+                    let name = match iter.next() {
+                        Some(Token::Identifier(name)) => name.clone(),
+                        _ => return Err(iter.error("expect a function name after def")),
+                    };
+                    let body = self.parse_function(&mut iter)?;
+                    functions.push(Function { name, arity: self.args.len(), body });
+                    match iter.next() {
+                        Some(Token::Symbol(';')) | None => (),
+                        Some(_) => return Err(iter.error("expect ; after the body of a function")),
+                    }
+                }
+            }
+        }
+        if functions.is_empty() {
+            let body = self.parse_function(&mut iter)?;
+            if iter.next().is_some() {
+                return Err(iter.error("unexpected token after the program"));
+            }
+            functions.push(Function { name: "main".to_string(), arity: self.args.len(), body });
+        }
+        let program = Program { functions };
+        program.check().map_err(CompileError::InvalidProgram)?;
+        Ok(program)
+    }
+
+    pub fn pass2(&mut self, ast : &Ast) -> Ast {
+        self.try_pass2(ast).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    // The tree is the body of a kata program's `main` as far as the user-defined passes know.
+    pub fn try_pass2(&mut self, ast : &Ast) -> Result<Ast, CompileError> {
+        let main = Function { name: "main".to_string(), arity: self.args.len(), body: self.reduce_body(ast)? };
+        let program = self.passes.run(&Program { functions: vec![main] })?;
+        Ok(program.functions.into_iter().next().unwrap().body)
+    }
+
+    fn reduce_body(&self, ast: &Ast) -> Result<Ast, CompileError> {
+        let ast = if self.float { ast.reduce_f64(0) } else { ast.reduce(0, self.arithmetic)? };
+        Ok(if self.cse { ast.eliminate_common_subtrees() } else { ast })
+    }
+
+    pub fn pass2_program(&mut self, program : &Program) -> Result<Program, CompileError> {
+        let mut program = program.clone();
+        if self.inline {
+            // each round inlines the functions whose own calls were all inlined in the previous one
+            loop {
+                let functions = program.functions.iter().map(|f| Function {
+                    body: f.body.inline_calls(&program, 0),
+                    ..f.clone()
+                }).collect();
+                if functions == program.functions {
+                    break;
+                }
+                program.functions = functions;
+            }
+            program.remove_unused();
+        }
+        for function in &mut program.functions {
+            function.body = self.reduce_body(&function.body)?;
+        }
+        self.passes.run(&program)
+    }
+
+    pub fn pass3(&mut self, ast : &Ast) -> Vec<String> {
+        let (code, spans) = self.emit_function("", ast);
+        layout(code, spans).0
+    }
+
+    // The code of one function, with labels and callees still referred to by name, and the
+    // span of the innermost node with a span that produced each instruction.
+    fn emit_function(&self, name: &str, body: &Ast) -> (Vec<String>, Vec<Option<Span>>) {
+        let mut result = Vec::new();
+        let mut frame = Frame { function: name.to_string(), ..Frame::default() };
+        match self.codegen {
+            Codegen::Naive => body.emit(&mut frame, &mut result),
+            Codegen::SethiUllman => body.emit_ordered(&mut frame, &mut result),
+        }
+        let mut spans = vec![None; result.len()];
+        for (range, span) in frame.sources {
+            for source in &mut spans[range] {
+                source.get_or_insert(span);
+            }
+        }
+        (result, spans)
+    }
+
+    // Lays out `main` first and the other functions after it, each labelled with its name and
+    // ending with `RT`. A program with only `main` that makes no calls compiles to exactly what
+    // `pass3` produces.
+    pub fn pass3_program(&mut self, program : &Program) -> Vec<String> {
+        self.pass3_program_with_spans(program).0
+    }
+
+    // `pass3_program` along with the source span of every instruction, if it has one.
+    pub fn pass3_program_with_spans(&mut self, program : &Program) -> (Vec<String>, Vec<Option<Span>>) {
+        let mut functions: Vec<&Function> = program.functions.iter().filter(|f| f.name == "main").collect();
+        functions.extend(program.functions.iter().filter(|f| f.name != "main"));
+        let mut callees = Vec::new();
+        program.functions.iter().for_each(|f| f.body.callees(&mut callees));
+        // a recursive `main` has to return from its calls instead of running off the end
+        let returns = program.functions.len() > 1 || !callees.is_empty();
+        let (mut result, mut spans) = (Vec::new(), Vec::new());
+        for function in functions {
+            result.push(format!("LB {}", function.name));
+            spans.push(None);
+            let (code, code_spans) = self.emit_function(&function.name, &function.body);
+            result.extend(code);
+            spans.extend(code_spans);
+            if returns {
+                result.push("RT".to_string());
+                spans.push(None);
+            }
+        }
+        layout(result, spans)
+    }
+
+    // The alternative pass3 for real hardware: GNU x86-64 assembly of `long symbol(long* args)`.
+    pub fn pass3_x86(&mut self, program : &Program, symbol: &str) -> String {
+        assert!(!self.float, "the x86-64 backend has no float mode");
+        x86::emit_program(program, symbol)
+    }
+
+    // C99 source of `int64_t symbol(const int64_t *args)`, with a `main` that runs it on the
+    // command line arguments if `with_main` is set.
+    pub fn pass3_c(&mut self, program : &Program, symbol: &str, with_main: bool) -> String {
+        assert!(!self.float, "the C backend has no float mode");
+        c::emit_program(program, symbol, with_main)
+    }
+
+    // A WebAssembly text module exporting `main` as `export`, with an i64 parameter per argument.
+    pub fn pass3_wat(&mut self, program : &Program, export: &str) -> String {
+        assert!(!self.float, "the WebAssembly backend has no float mode");
+        wat::emit_program(program, export)
+    }
+
+    // Nested Rust closures that evaluate `main` in process, for running a program many times.
+    pub fn pass3_closure(&mut self, program : &Program) -> Closure {
+        assert!(!self.float, "closures compute with i64 and have no float mode");
+        closure::compile_program(program)
+    }
+
+    // Compiles the program with every code generator and reports the size of the output.
+    pub fn codegen_report(&mut self, program: &str) -> Result<String, CompileError> {
+        let program = self.try_pass1_program(program)?;
+        let program = self.pass2_program(&program)?;
+        let saved = self.codegen;
+        let mut report = String::new();
+        for &codegen in &[Codegen::Naive, Codegen::SethiUllman] {
+            self.codegen = codegen;
+            let stats = CodeStats::of(&self.pass3_program(&program));
+            report.push_str(&format!("{:?}: {}\n", codegen, stats));
+        }
+        self.codegen = saved;
+        Ok(report)
+    }
+}
+
+// The final layout step: drops the `LB label` pseudo-instructions and replaces the labels
+// that `JP`, `JZ` and `CA` refer to with the address of the instruction that follows them.
+fn layout(code: Vec<String>, spans: Vec<Option<Span>>) -> (Vec<String>, Vec<Option<Span>>) {
+    let mut addresses = HashMap::new();
+    let mut address = 0;
+    for instr in &code {
+        match instr.strip_prefix("LB ") {
+            Some(label) => {
+                addresses.insert(label.to_string(), address);
+            },
+            None => address += 1,
+        }
+    }
+    code.into_iter().zip(spans).filter(|(instr, _)| !instr.starts_with("LB ")).map(|(instr, span)| {
+        let mut parts: Vec<String> = instr.split(' ').map(String::from).collect();
+        if ["JP", "JZ", "CA"].contains(&parts[0].as_str()) {
+            parts[1] = match addresses.get(&parts[1]) {
+                Some(address) => address.to_string(),
+                None => panic!("undefined label {}", parts[1]),
+            };
+        }
+        (parts.join(" "), span)
+    }).unzip()
+}
+
+// Runs the assembly on the kata's two-register stack machine, extended with
+// - `LD n` / `ST n` to load R0 from / store R0 into the n-th stack slot of the current call,
+// - `MO` for the remainder of R0 / R1,
+// - `LT`, `LE`, `GT`, `GE`, `EQ` and `NE` to set R0 to 1 if R0 compares so to R1 and to 0 otherwise,
+// - `NG` to negate R0,
+// - `JP addr` to jump to `addr`, and `JZ addr` to jump there only if R0 is 0,
+// - `CA addr n` to call the function at `addr` with the top n stack values as its arguments,
+// - `RT` to return to the caller, or to stop when returning from `main`.
+pub fn simulate(asm: &[String], args: &[i64]) -> i64 {
+    try_simulate(asm, args).unwrap_or_else(|e| panic!("{}", e))
+}
+
+// The machine in the float mode, where registers, stack slots and arguments are f64 and
+// `IM` takes decimal immediates. Division by zero follows IEEE rules instead of stopping.
+pub fn simulate_f64(asm: &[String], args: &[f64]) -> f64 {
+    run(asm, args).unwrap_or_else(|e| panic!("{}", e))
+}
+
+// What the machine and the tree walker compute with: i64, or f64 in the float mode.
+trait Word: Copy + PartialEq + fmt::Debug {
+    fn from_imm(x: i64) -> Self;
+    fn from_float(x: f64) -> Self;
+    fn parse(text: &str) -> Self;
+    fn apply(op: BinOp, x: Self, y: Self) -> Result<Self, Trap>;
+    fn negate(self) -> Result<Self, Trap>;
+    fn is_zero(self) -> bool;
+}
+
+impl Word for i64 {
+    fn from_imm(x: i64) -> i64 { x }
+
+    fn from_float(x: f64) -> i64 { panic!("float literal {:?} outside the float mode", x) }
+
+    fn parse(text: &str) -> i64 { text.parse().unwrap() }
+
+    fn apply(op: BinOp, x: i64, y: i64) -> Result<i64, Trap> {
+        let result = match op {
+            BinOp::Div | BinOp::Mod if y == 0 => return Err(Trap::DivisionByZero),
+            BinOp::Add => x.checked_add(y),
+            BinOp::Sub => x.checked_sub(y),
+            BinOp::Mul => x.checked_mul(y),
+            BinOp::Div => x.checked_div(y),
+            BinOp::Mod => x.checked_rem(y),
+            _ => Some(op.evaluate(x, y)),
+        };
+        result.ok_or(Trap::Overflow)
+    }
+
+    fn negate(self) -> Result<i64, Trap> { self.checked_neg().ok_or(Trap::Overflow) }
+
+    fn is_zero(self) -> bool { self == 0 }
+}
+
+impl Word for f64 {
+    fn from_imm(x: i64) -> f64 { x as f64 }
+
+    fn from_float(x: f64) -> f64 { x }
+
+    fn parse(text: &str) -> f64 { text.parse().unwrap() }
+
+    fn apply(op: BinOp, x: f64, y: f64) -> Result<f64, Trap> { Ok(op.evaluate_f64(x, y)) }
+
+    fn negate(self) -> Result<f64, Trap> { Ok(-self) }
+
+    fn is_zero(self) -> bool { self == 0.0 }
+}
+
+// What stops the machine, or the tree walker, in the middle of an operator.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Trap {
+    DivisionByZero,
+    Overflow,
+}
+
+impl Trap {
+    fn at(self, address: usize) -> RuntimeError {
+        match self {
+            Trap::DivisionByZero => RuntimeError::DivisionByZero { address },
+            Trap::Overflow => RuntimeError::Overflow { address },
+        }
+    }
+
+    fn message(self) -> &'static str {
+        match self {
+            Trap::DivisionByZero => "division by zero",
+            Trap::Overflow => "arithmetic overflow",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RuntimeError {
+    // `DI` or `MO` at `address` with R1 = 0
+    DivisionByZero { address: usize },
+    // an i64 operator at `address` whose result does not fit
+    Overflow { address: usize },
+}
+
+impl RuntimeError {
+    pub fn address(self) -> usize {
+        match self {
+            RuntimeError::DivisionByZero { address } | RuntimeError::Overflow { address } => address,
+        }
+    }
+
+    // The error without its address.
+    pub fn message(self) -> &'static str {
+        match self {
+            RuntimeError::DivisionByZero { .. } => Trap::DivisionByZero.message(),
+            RuntimeError::Overflow { .. } => Trap::Overflow.message(),
+        }
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at instruction {}", self.message(), self.address())
+    }
+}
+
+pub fn try_simulate(asm: &[String], args: &[i64]) -> Result<i64, RuntimeError> {
+    run(asm, args)
+}
+
+fn run<T: Word>(asm: &[String], args: &[T]) -> Result<T, RuntimeError> {
+    struct Call<T> {
+        return_address: usize,
+        args: Vec<T>,
+        base: usize,
+    }
+
+    let (mut r0, mut r1) = (T::from_imm(0), T::from_imm(0));
+    let mut stack: Vec<T> = Vec::new();
+    let mut calls: Vec<Call<T>> = Vec::new();
+    let mut args = args.to_vec();
+    // where the stack slots of the current call begin
+    let mut base = 0usize;
+    let mut pc = 0usize;
+    while pc < asm.len() {
+        let instr = &asm[pc];
+        pc += 1;
+        let parts: Vec<&str> = instr.split_whitespace().collect();
+        let operand = |i: usize| -> usize { parts[i].parse().unwrap() };
+        match parts.first().copied().unwrap_or("") {
+            "IM" => r0 = T::parse(parts[1]),
+            "AR" => r0 = args[operand(1)],
+            "SW" => std::mem::swap(&mut r0, &mut r1),
+            "PU" => stack.push(r0),
+            "PO" => r0 = stack.pop().unwrap(),
+            "LD" => r0 = stack[base + operand(1)],
+            "ST" => {
+                let slot = base + operand(1);
+                stack[slot] = r0;
+            },
+            "JP" => pc = operand(1),
+            "JZ" => {
+                if r0.is_zero() {
+                    pc = operand(1);
+                }
+            },
+            "CA" => {
+                let (address, argc) = (operand(1), operand(2));
+                let callee_args = stack.split_off(stack.len() - argc);
+                calls.push(Call { return_address: pc, args: std::mem::replace(&mut args, callee_args), base });
+                base = stack.len();
+                pc = address;
+            },
+            "NG" => r0 = r0.negate().map_err(|trap| trap.at(pc - 1))?,
+            "RT" => match calls.pop() {
+                Some(call) => {
+                    pc = call.return_address;
+                    args = call.args;
+                    base = call.base;
+                },
+                None => break,
+            },
+            op => match BinOp::from_instruction(op) {
+                Some(op) => r0 = T::apply(op, r0, r1).map_err(|trap| trap.at(pc - 1))?,
+                None => panic!("unrecognized instruction {}", instr),
+            },
+        }
+    }
+    Ok(r0)
+}
+
+#[test]
+fn sethi_ullman_matches_naive() {
+    let programs = [
+        ("[ x y z ] ( 2*3*x + 5*y - 3*z ) / (1 + 3 + 2*2)", vec![4, 0, 0]),
+        ("[ a b ] a - b", vec![7, 2]),
+        ("[ a b ] 10 / a - (b - a) * (b + a)", vec![2, 5]),
+        ("[ a b c d ] (a - b) / (c - d) - a * (b - (c - d))", vec![9, 3, 7, 4]),
+    ];
+    for (program, args) in programs.iter() {
+        let mut naive = Compiler::new();
+        let mut ordered = Compiler::new();
+        ordered.codegen = Codegen::SethiUllman;
+        let expected = simulate(&naive.compile(program), args);
+        assert_eq!(simulate(&ordered.compile(program), args), expected, "{}", program);
+    }
+}
+
+#[test]
+fn sethi_ullman_avoids_stack_traffic() {
+    let mut compiler = Compiler::new();
+    compiler.codegen = Codegen::SethiUllman;
+    let asm = compiler.compile("[ a b ] a * 2 - b");
+    assert_eq!(asm, vec!["IM 2", "SW", "AR 0", "MU", "SW", "AR 1", "SW", "SU"]);
+    assert_eq!(CodeStats::of(&asm), CodeStats { instructions: 8, max_stack_depth: 0 });
+
+    let asm = compiler.compile("[ a b c d ] (a + b) * (c + d)");
+    assert_eq!(CodeStats::of(&asm).max_stack_depth, 1);
+}
+
+#[test]
+fn pass2_applies_algebraic_identities() {
+    let mut compiler = Compiler::new();
+    let mut reduce = |program: &str| {
+        let ast = compiler.pass1(program);
+        compiler.pass2(&ast).to_string()
+    };
+    assert_eq!(reduce("[ x ] x * 1 + 0"), "(arg 0)");
+    assert_eq!(reduce("[ x ] 1 * (0 + x) / 1 - 0"), "(arg 0)");
+    assert_eq!(reduce("[ x y ] x * 0 + (y - y)"), "(imm 0)");
+    assert_eq!(reduce("[ a ] (a + 2) + 3"), "(+ (arg 0) (imm 5))");
+    // constants only move past other constants, since moving them past a variable could overflow
+    assert_eq!(reduce("[ a b ] 2 * (a * 3) * (4 * b)"), "(* (* (arg 0) (imm 6)) (* (imm 4) (arg 1)))");
+    assert_eq!(reduce("[ a b ] (a + 1) + (b + 2) + 3"), "(+ (+ (+ (arg 0) (imm 1)) (+ (arg 1) (imm 2))) (imm 3))");
+}
+
+#[test]
+fn pass2_respects_integer_division() {
+    let mut compiler = Compiler::new();
+    let mut reduce = |program: &str| {
+        let ast = compiler.pass1(program);
+        compiler.pass2(&ast).to_string()
+    };
+    assert_eq!(reduce("[ a ] a / 2 * 2"), "(* (/ (arg 0) (imm 2)) (imm 2))");
+    assert_eq!(reduce("[ a ] 7 / 2 + a"), "(+ (imm 3) (arg 0))");
+    assert_eq!(reduce("[ a b ] a / b * 0"), "(* (/ (arg 0) (arg 1)) (imm 0))");
+    assert_eq!(reduce("[ a b ] a / b - a / b"), "(- (/ (arg 0) (arg 1)) (/ (arg 0) (arg 1)))");
+    assert_eq!(reduce("[ a ] a / 2 * 0"), "(imm 0)");
+    assert_eq!(reduce("[ a ] a / 0 * 0"), "(* (/ (arg 0) (imm 0)) (imm 0))");
+}
+
+#[test]
+fn constant_folding_reports_division_by_zero() {
+    let mut compiler = Compiler::new();
+    assert_eq!(
+        compiler.try_compile("[] 1 / 0"),
+        Err(CompileError::DivisionByZero("(/ (imm 1) (imm 0))".to_string())),
+    );
+    assert!(compiler.try_compile("[ a ] a + 4 / (2 - 2)").is_err());
+    assert!(compiler.try_compile("[ a ] a / 0").is_ok());
+}
+
+#[test]
+fn constant_folding_follows_arithmetic_policy() {
+    let mut compiler = Compiler::new();
+    let ast = Ast::bin(BinOp::Mul, Ast::imm(i64::MAX), Ast::bin(BinOp::Add, Ast::imm(1), Ast::imm(1)));
+    assert_eq!(
+        compiler.try_pass2(&ast),
+        Err(CompileError::Overflow(format!("(* (imm {}) (imm 2))", i64::MAX))),
+    );
+    compiler.arithmetic = Arithmetic::Wrapping;
+    assert_eq!(compiler.pass2(&ast), Ast::imm(-2));
+    compiler.arithmetic = Arithmetic::Saturating;
+    assert_eq!(compiler.pass2(&ast), Ast::imm(i64::MAX));
+
+    // (e + c1) + c2 => e + (c1 + c2) overflows exactly when the program does, but not when
+    // c1 + c2 alone overflows
+    let ast = Ast::bin(BinOp::Add, Ast::bin(BinOp::Add, Ast::arg(0), Ast::imm(i64::MAX)), Ast::imm(1));
+    compiler.arithmetic = Arithmetic::Checked;
+    assert_eq!(compiler.pass2(&ast), ast);
+
+    // a constant never moves past a variable, since e1 + e2 may overflow where e1 + (e2 + c) does not
+    let cases: &[(&str, [i64; 2])] = &[
+        ("[ a b ] a + (b + -5)", [i64::MAX, 1]),
+        ("[ a b ] (a + -5) + b", [i64::MAX, 1]),
+        ("[ a b ] (a + 5) + b", [i64::MIN, -1]),
+        ("[ a b ] a * (b * -1)", [i64::MIN, -1]),
+    ];
+    for (source, args) in cases {
+        let program = compiler.pass1_program(source);
+        let reduced = compiler.pass2_program(&program).unwrap();
+        let asm = compiler.pass3_program(&reduced);
+        assert_eq!(try_simulate(&asm, args).ok(), Some(program.eval(args)), "{} {:?}", source, args);
+    }
+}
+
+#[test]
+fn tagged_ast_round_trips() {
+    let mut compiler = Compiler::new();
+    let ast = compiler.pass1("[ x y ] (x + 2) * y / 3 - 1");
+    let tagged = TaggedAst::from(&ast);
+    assert_eq!(Ast::try_from(&tagged), Ok(ast));
+
+    let bogus = TaggedAst::BinOp("^".to_string(), Box::new(TaggedAst::UnOp("imm".to_string(), 1)), Box::new(TaggedAst::UnOp("imm".to_string(), 2)));
+    assert_eq!(Ast::try_from(&bogus), Err(CompileError::UnknownOperator("^".to_string())));
+}
+
+#[test]
+fn let_bindings_are_inlined_or_kept_by_use_count() {
+    let mut compiler = Compiler::new();
+    let mut reduce = |program: &str| {
+        let ast = compiler.pass1(program);
+        compiler.pass2(&ast).to_string()
+    };
+    assert_eq!(reduce("[ a ] let x = a + 1 in x * x"), "(let (+ (arg 0) (imm 1)) (* (var 0) (var 0)))");
+    assert_eq!(reduce("[ a ] let x = 2 + 3 in a * x * x"), "(* (arg 0) (imm 25))");
+    assert_eq!(reduce("[ a ] let x = a + 2 in x + 3"), "(+ (arg 0) (imm 5))");
+    assert_eq!(reduce("[ a ] let x = a * 7 in 1"), "(imm 1)");
+    assert_eq!(reduce("[ a ] let x = 1 / a in 1"), "(let (/ (imm 1) (arg 0)) (imm 1))");
+    assert_eq!(
+        reduce("[ a b ] let x = a + b in let y = x * x in let x = y - a in x / y + x"),
+        "(let (+ (arg 0) (arg 1)) (let (* (var 0) (var 0)) (let (- (var 1) (arg 0)) (+ (/ (var 2) (var 1)) (var 2)))))",
+    );
+    // the let inside the value binds one level deeper where the value is used
+    assert_eq!(
+        reduce("[ a b ] let x = (let y = a / b in y + y) in let z = a * b in z * z - x"),
+        "(let (* (arg 0) (arg 1)) (- (* (var 0) (var 0)) (let (/ (arg 0) (arg 1)) (+ (var 1) (var 1)))))",
+    );
+}
+
+#[test]
+fn let_bindings_live_in_stack_slots() {
+    let program = "[ a b ] let s = a + b in let d = a - b in (s * d) / (s - d + 1)";
+    for &codegen in &[Codegen::Naive, Codegen::SethiUllman] {
+        let mut compiler = Compiler::new();
+        compiler.codegen = codegen;
+        let asm = compiler.compile(program);
+        assert!(asm.iter().any(|instr| instr.starts_with("LD")));
+        assert_eq!(simulate(&asm, &[7, 3]), 40 / 7);
+    }
+}
+
+#[test]
+fn negation_and_modulo() {
+    let mut compiler = Compiler::new();
+    let ast = compiler.pass1("[ a b ] -a * b % -3 - -(-2)");
+    assert_eq!(ast.to_string(), "(- (% (* (- (imm 0) (arg 0)) (arg 1)) (imm -3)) (- (imm 0) (imm -2)))");
+    assert_eq!(compiler.pass2(&ast).to_string(), "(- (% (* (- (imm 0) (arg 0)) (arg 1)) (imm -3)) (imm 2))");
+    let ast = compiler.pass1("[ a ] - - a + -7 % 4 + a % 1");
+    assert_eq!(compiler.pass2(&ast).to_string(), "(+ (arg 0) (imm -3))");
+    assert_eq!(compiler.try_compile("[ a ] a + 7 % (3 - 3)"), Err(CompileError::DivisionByZero("(% (imm 7) (imm 0))".to_string())));
+
+    for &codegen in &[Codegen::Naive, Codegen::SethiUllman] {
+        compiler.codegen = codegen;
+        let asm = compiler.compile("[ a b ] -a % b - -(b % 3)");
+        assert_eq!(simulate(&asm, &[-17, 5]), 17 % 5 + 5 % 3);
+    }
+}
+
+#[test]
+fn functions_call_each_other() {
+    let program = "def sq [x] x * x; def main [a b] sq(a) + sq(b) - dist(a, b); def dist [x y] sq(x - y)";
+    for &codegen in &[Codegen::Naive, Codegen::SethiUllman] {
+        let mut compiler = Compiler::new();
+        compiler.codegen = codegen;
+        let asm = compiler.compile(program);
+        assert_eq!(simulate(&asm, &[3, 4]), 9 + 16 - 1);
+        assert_eq!(asm.iter().filter(|instr| *instr == "RT").count(), 3);
+    }
+    // a kata program compiles exactly as before
+    let mut compiler = Compiler::new();
+    let ast = compiler.pass1("[ a b ] a * b");
+    assert_eq!(compiler.compile("[ a b ] a * b"), compiler.pass3(&ast));
+}
+
+#[test]
+fn small_functions_are_inlined() {
+    let mut compiler = Compiler::new();
+    compiler.inline = true;
+    let program = compiler.pass1_program("def sq [x] x * x; def dist [x y] sq(x - y); def main [a b] dist(a, b) + sq(3)");
+    let program = compiler.pass2_program(&program).unwrap();
+    assert_eq!(
+        program.to_string(),
+        "(def main 2 (+ (let (- (arg 0) (arg 1)) (* (var 0) (var 0))) (imm 9)))\n",
+    );
+    assert_eq!(simulate(&compiler.pass3_program(&program), &[2, 7]), 34);
+}
+
+#[test]
+fn conditionals_jump_over_the_branch_not_taken() {
+    let program = "def max [a b] if a > b then a else b; def main [a b c] if a == max(a, max(b, c)) then 1 else -1";
+    for &codegen in &[Codegen::Naive, Codegen::SethiUllman] {
+        let mut compiler = Compiler::new();
+        compiler.codegen = codegen;
+        let asm = compiler.compile(program);
+        assert_eq!(simulate(&asm, &[9, 4, 7]), 1);
+        assert_eq!(simulate(&asm, &[3, 4, 7]), -1);
+
+        let asm = compiler.compile("def fact [n] if n <= 1 then 1 else n * fact(n - 1); def main [n] fact(n)");
+        assert_eq!(simulate(&asm, &[10]), 3628800);
+
+        let asm = compiler.compile("def main [n] if n <= 0 then 0 else n + main(n - 1)");
+        assert_eq!(simulate(&asm, &[3]), 6);
+    }
+
+    let mut compiler = Compiler::new();
+    let ast = compiler.pass1("[ a b ] if a != 0 then b / a else 0");
+    assert_eq!(ast.to_string(), "(if (!= (arg 0) (imm 0)) (/ (arg 1) (arg 0)) (imm 0))");
+    assert_eq!(simulate(&compiler.pass3(&ast), &[0, 5]), 0);
+    assert_eq!(
+        compiler.pass3(&ast),
+        vec![
+            "AR 0", "SW", "PU", "IM 0", "SW", "NE", "SW", "PO", "SW", "JZ 20",
+            "AR 1", "SW", "PU", "AR 0", "SW", "DI", "SW", "PO", "SW", "JP 21",
+            "IM 0",
+        ],
+    );
+}
+
+#[test]
+fn pass2_folds_conditions_without_hiding_traps() {
+    let mut compiler = Compiler::new();
+    let mut reduce = |program: &str| {
+        let ast = compiler.pass1(program);
+        compiler.try_pass2(&ast).map(|ast| ast.to_string())
+    };
+    assert_eq!(reduce("[ a ] if 2 < 3 then a else 1 / 0"), Ok("(arg 0)".to_string()));
+    assert_eq!(reduce("[ a ] if a >= 3 then a + 1 else (a + 0) + 1"), Ok("(+ (arg 0) (imm 1))".to_string()));
+    assert_eq!(reduce("[ a ] (a == 1) == 1"), Ok("(== (== (arg 0) (imm 1)) (imm 1))".to_string()));
+    assert_eq!(
+        reduce("[ a b ] let x = b / a in if a then x else 0"),
+        Ok("(let (/ (arg 1) (arg 0)) (if (arg 0) (var 0) (imm 0)))".to_string()),
+    );
+    assert_eq!(
+        reduce("[ a b ] let x = b * a in if a then x else 0"),
+        Ok("(if (arg 0) (* (arg 1) (arg 0)) (imm 0))".to_string()),
+    );
+}
+
+#[test]
+fn common_subexpressions_are_computed_once() {
+    let mut compiler = Compiler::new();
+    let ast = compiler.pass1("[ a b ] (a + b) * (a + b)");
+    assert_eq!(compiler.pass2(&ast), ast);
+    compiler.cse = true;
+    assert_eq!(compiler.pass2(&ast).to_string(), "(let (+ (arg 0) (arg 1)) (* (var 0) (var 0)))");
+
+    let ast = compiler.pass1("[ a b ] (a + b) * b + (a + b) * b - (a + b)");
+    assert_eq!(
+        compiler.pass2(&ast).to_string(),
+        "(let (+ (arg 0) (arg 1)) (let (* (var 0) (arg 1)) (- (+ (var 1) (var 1)) (var 0))))",
+    );
+    // a subexpression that only some branches evaluate stays where it is
+    let ast = compiler.pass1("[ a b ] if a > b then a / b else (a / b) * 2");
+    assert_eq!(compiler.pass2(&ast), compiler.pass1("[ a b ] if a > b then a / b else (a / b) * 2"));
+
+    // long sums are hash-consed in a single pass rather than one pass per repeated subtree
+    let terms: Vec<String> = (0..200).map(|i| format!("(a * b - {})", i % 10)).collect();
+    let source = format!("[ a b ] {}", terms.join(" + "));
+    let parsed = compiler.pass1(&source);
+    let reduced = compiler.pass2(&parsed);
+    assert_eq!(reduced.to_string().matches("(let").count(), 10);
+    assert_eq!(simulate(&compiler.pass3(&reduced), &[3, 4]), (0..200).map(|i| 12 - i % 10).sum());
+
+    let program = "def f [x] x * x; def main [a b] let c = a - b in f(a + b) * c + f(a + b) / (a * b) + a * b";
+    for &codegen in &[Codegen::Naive, Codegen::SethiUllman] {
+        compiler.codegen = codegen;
+        compiler.cse = false;
+        let expected = simulate(&compiler.compile(program), &[5, 2]);
+        compiler.cse = true;
+        assert_eq!(simulate(&compiler.compile(program), &[5, 2]), expected);
+    }
+}
+
+#[test]
+fn instructions_map_back_to_the_source() {
+    let source = "[ a b ] a * 2 + b / (a - 1)";
+    let mut compiler = Compiler::new();
+    let program = compiler.pass1_program(source);
+    let program = compiler.pass2_program(&program).unwrap();
+    let (asm, spans) = compiler.pass3_program_with_spans(&program);
+    assert_eq!(asm.len(), spans.len());
+    assert!(spans.iter().all(Option::is_some));
+
+    let error = try_simulate(&asm, &[1, 5]).unwrap_err();
+    let address = error.address();
+    assert_eq!(error, RuntimeError::DivisionByZero { address });
+    assert_eq!(asm[address], "DI");
+    let span = spans[address].unwrap();
+    assert_eq!(&source[span.start..span.end], "/");
+    assert_eq!(span.line_column(source), (1, 19));
+    assert_eq!(span.underline(source), format!("{}\n{}^", source, " ".repeat(18)));
+
+    // spans survive constant folding where the node itself survives
+    let program = compiler.pass1_program("[ a ] (a + 2) + 3");
+    let program = compiler.pass2_program(&program).unwrap();
+    assert_eq!(program.functions[0].body, Ast::bin(BinOp::Add, Ast::arg(0), Ast::imm(5)));
+    assert!(matches!(program.functions[0].body, Ast::At(Span { start: 14, end: 15 }, _)));
+}
+
+#[test]
+fn lexer_reads_hex_and_64_bit_literals_and_reports_errors() {
+    let compiler = Compiler::new();
+    assert_eq!(compiler.tokenize("[ x_1 y2 ]\n\tx_1 * 0xFf -\r\n  y2 / 9223372036854775807"), vec!["[", "x_1", "y2", "]", "x_1", "*", "255", "-", "y2", "/", "9223372036854775807"]);
+    assert_eq!(Compiler::new().pass1("[ _a ] _a * 0x10 + 5000000000"), Ast::bin(BinOp::Add, Ast::bin(BinOp::Mul, Ast::arg(0), Ast::imm(16)), Ast::imm(5000000000)));
+
+    let source = "[ a ]\n  a + 9223372036854775808";
+    let error = compiler.try_tokenize(source).unwrap_err();
+    assert_eq!(error, LexError::Overflow { literal: "9223372036854775808".to_string(), span: Span { start: 12, end: 31 } });
+    assert_eq!(error.describe(source), "literal 9223372036854775808 does not fit in 64 bits at line 2, column 7");
+    assert_eq!(compiler.try_tokenize("[ a ] a $ 1"), Err(LexError::UnknownCharacter { character: '$', span: Span { start: 8, end: 9 } }));
+    assert_eq!(compiler.try_tokenize("[ a ] 0xg"), Err(LexError::InvalidLiteral { literal: "0xg".to_string(), span: Span { start: 6, end: 9 } }));
+    assert_eq!(compiler.try_tokenize("[ a ] 3a"), Err(LexError::InvalidLiteral { literal: "3a".to_string(), span: Span { start: 6, end: 8 } }));
+}
+
+#[test]
+fn pass1_reports_syntax_and_program_errors() {
+    let mut compiler = Compiler::new();
+    let syntax = |message: &str, start, end| Err(CompileError::Syntax { message: message.to_string(), span: Span { start, end } });
+    assert_eq!(compiler.try_pass1_program("[ a ] a $"), Err(CompileError::Lex(LexError::UnknownCharacter { character: '$', span: Span { start: 8, end: 9 } })));
+    assert_eq!(compiler.try_pass1_program("[ a ] a +"), syntax("expect a number, variable or parenthesised expression before the end of the input", 9, 9));
+    assert_eq!(compiler.try_pass1_program("def main [a] a; 5"), syntax("expect def", 16, 17));
+    assert_eq!(compiler.try_pass1_program("[ a ] a 5"), syntax("unexpected token after the program", 8, 9));
+    assert_eq!(compiler.try_pass1_program("def main [a] g(a)"), Err(CompileError::InvalidProgram("undefined function g".to_string())));
+    assert_eq!(compiler.try_pass1_program("def f [] 1"), Err(CompileError::InvalidProgram("program has no main function".to_string())));
+
+    let source = "[ a ]\n  a < a < a";
+    let error = compiler.try_pass1_program(source).unwrap_err();
+    assert_eq!(error.describe(source), "comparisons cannot be chained at line 2, column 9");
+}
+
+#[test]
+fn float_mode_follows_ieee_rules() {
+    let mut compiler = Compiler::new();
+    compiler.float = true;
+    let mut reduce = |program: &str| {
+        let ast = compiler.pass1(program);
+        compiler.pass2(&ast).to_string()
+    };
+    assert_eq!(reduce("[ a ] a / 2 + 7 / 2"), "(+ (/ (arg 0) (float 2.0)) (float 3.5))");
+    assert_eq!(reduce("[ a ] (a * 0 + 0) * 1"), "(* (+ (* (arg 0) (float 0.0)) (float 0.0)) (float 1.0))");
+    assert_eq!(reduce("[] -1 / 0 + 5.5 % 2"), "(float -inf)");
+    assert_eq!(reduce("[ a ] if 0 / 0.0 then a else -0.0"), "(arg 0)");
+    assert_eq!(reduce("[ a ] let h = 0.5 in a * h * h"), "(* (* (arg 0) (float 0.5)) (float 0.5))");
+    assert_eq!(Compiler::new().tokenize("[ a ] a * 1.25"), vec!["[", "a", "]", "a", "*", "1.25"]);
+    assert_eq!(Compiler::new().try_tokenize("[ a ] 1.e5"), Err(LexError::InvalidLiteral { literal: "1.e5".to_string(), span: Span { start: 6, end: 10 } }));
+
+    let program = compiler.pass1_program("def half [x] x / 2; def main [a b] if a / b < 0.1 then half(a) else a % b");
+    let reduced = compiler.pass2_program(&program).unwrap();
+    let asm = compiler.pass3_program(&reduced);
+    assert!(asm.contains(&"IM 2.0".to_string()) && asm.contains(&"IM 0.1".to_string()));
+    for (args, expected) in &[([7.0, 2.0], 1.0), ([0.3, 100.0], 0.15), ([10.0, 4.0], 2.0), ([-1.0, 0.0], -0.5)] {
+        assert_eq!(program.eval_f64(args), *expected);
+        assert_eq!(simulate_f64(&asm, args), *expected);
+    }
+    assert!(simulate_f64(&asm, &[1.0, 0.0]).is_nan());
+
+    // -a is not 0 - a, which is 0.0 for a = 0.0
+    for &codegen in &[Codegen::Naive, Codegen::SethiUllman] {
+        compiler.codegen = codegen;
+        let program = compiler.pass1_program("[ a b ] -a * -(b + 1)");
+        let reduced = compiler.pass2_program(&program).unwrap();
+        let asm = compiler.pass3_program(&reduced);
+        assert_eq!(asm.iter().filter(|instr| *instr == "NG").count(), 2);
+        for args in &[[0.0, 1.0], [0.0, -2.0], [-0.0, 1.0], [2.0, f64::INFINITY]] {
+            let expected = -args[0] * -(args[1] + 1.0);
+            assert_eq!(program.eval_f64(args).to_bits(), expected.to_bits(), "{:?}", args);
+            assert_eq!(simulate_f64(&asm, args).to_bits(), expected.to_bits(), "{:?}", args);
+        }
+    }
+}
@@ -0,0 +1,299 @@
+use std::env;
+use std::fs;
+use std::io::{self, Read};
+use std::process;
+
+use tiny_three_pass_compiler::{interleave, three_address, simulate_f64, try_simulate, Arithmetic, Codegen, Compiler, Program, StrengthReduction};
+
+const USAGE: &str = "\
+usage: tpc [options] [FILE]
+
+Compiles the program in FILE, or standard input if FILE is omitted or `-`.
+
+options:
+    --emit tokens|ast|ast-opt|asm|x86-64|wat|c
+                                      stop after the given pass and print its output (default: asm),
+                                      x86-64 being GNU assembly of `long f(long* args)`, wat
+                                      a WebAssembly module exporting `f` and c the C source of
+                                      `int64_t f(const int64_t *args)`
+    --with-main                       add a main reading the arguments from argv to --emit c
+    --json                            print ASTs in the kata's JSON format
+    --run ARG...                      run the compiled program with the given arguments
+    --stats                           compare the output size of every code generator
+    --codegen naive|sethi-ullman      code generator used by pass3 (default: naive)
+    --arithmetic checked|wrapping|saturating
+                                      overflow policy of constant folding (default: checked)
+    --interleave                      print the source of the instructions along with them
+    --inline                          inline calls of small functions
+    --cse                             compute repeated subexpressions only once
+    --strength-reduce                 replace multiplications of a variable by 2 with additions
+    --float                           decimal literals and f64 arithmetic, for asm and --run only
+    --dump-after pass1,pass2,pass3    print the output of the given passes to standard error, ASTs in
+                                      the --dump-format and the code of pass3 in three-address form
+    --dump-format sexpr|dot           indented S-expressions or Graphviz DOT (default: sexpr)
+
+Options taking a value may also be written --option=value.";
+
+#[derive(Clone, Copy, PartialEq)]
+enum Pass {
+    Pass1,
+    Pass2,
+    Pass3,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum DumpFormat {
+    SExpr,
+    Dot,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Emit {
+    Tokens,
+    Ast,
+    AstOpt,
+    Asm,
+    X86,
+    Wat,
+    C,
+}
+
+struct Options {
+    emit: Option<Emit>,
+    json: bool,
+    run: Option<Vec<String>>,
+    stats: bool,
+    codegen: Codegen,
+    arithmetic: Arithmetic,
+    inline: bool,
+    cse: bool,
+    strength_reduce: bool,
+    float: bool,
+    with_main: bool,
+    interleave: bool,
+    dump_after: Vec<Pass>,
+    dump_format: DumpFormat,
+    file: Option<String>,
+}
+
+fn parse_options(args: &[String]) -> Result<Options, String> {
+    let mut options = Options {
+        emit: None,
+        json: false,
+        run: None,
+        stats: false,
+        codegen: Codegen::Naive,
+        arithmetic: Arithmetic::Checked,
+        inline: false,
+        cse: false,
+        strength_reduce: false,
+        float: false,
+        with_main: false,
+        interleave: false,
+        dump_after: Vec::new(),
+        dump_format: DumpFormat::SExpr,
+        file: None,
+    };
+    let mut iter = args.iter().peekable();
+    while let Some(arg) = iter.next() {
+        let (arg, inline_value) = match arg.split_once('=') {
+            Some((name, value)) if name.starts_with("--") => (name, Some(value.to_string())),
+            _ => (arg.as_str(), None),
+        };
+        let mut value = |name: &str| match inline_value.clone() {
+            Some(value) => Ok(value),
+            None => iter.next().cloned().ok_or(format!("{} needs a value", name)),
+        };
+        match arg {
+            "--emit" => options.emit = Some(match value("--emit")?.as_str() {
+                "tokens" => Emit::Tokens,
+                "ast" => Emit::Ast,
+                "ast-opt" => Emit::AstOpt,
+                "asm" => Emit::Asm,
+                "x86-64" => Emit::X86,
+                "wat" => Emit::Wat,
+                "c" => Emit::C,
+                other => return Err(format!("unknown pass {:?}", other)),
+            }),
+            "--codegen" => options.codegen = match value("--codegen")?.as_str() {
+                "naive" => Codegen::Naive,
+                "sethi-ullman" => Codegen::SethiUllman,
+                other => return Err(format!("unknown code generator {:?}", other)),
+            },
+            "--arithmetic" => options.arithmetic = match value("--arithmetic")?.as_str() {
+                "checked" => Arithmetic::Checked,
+                "wrapping" => Arithmetic::Wrapping,
+                "saturating" => Arithmetic::Saturating,
+                other => return Err(format!("unknown arithmetic policy {:?}", other)),
+            },
+            "--dump-after" => for pass in value("--dump-after")?.split(',') {
+                options.dump_after.push(match pass {
+                    "pass1" => Pass::Pass1,
+                    "pass2" => Pass::Pass2,
+                    "pass3" => Pass::Pass3,
+                    other => return Err(format!("unknown pass {:?}", other)),
+                });
+            },
+            "--dump-format" => options.dump_format = match value("--dump-format")?.as_str() {
+                "sexpr" => DumpFormat::SExpr,
+                "dot" => DumpFormat::Dot,
+                other => return Err(format!("unknown dump format {:?}", other)),
+            },
+            "--json" => options.json = true,
+            "--stats" => options.stats = true,
+            "--inline" => options.inline = true,
+            "--cse" => options.cse = true,
+            "--strength-reduce" => options.strength_reduce = true,
+            "--float" => options.float = true,
+            "--with-main" => options.with_main = true,
+            "--interleave" => options.interleave = true,
+            "--run" => {
+                let mut run_args = Vec::new();
+                while let Some(x) = iter.peek().filter(|x| x.parse::<f64>().is_ok()) {
+                    run_args.push(x.to_string());
+                    iter.next();
+                }
+                options.run = Some(run_args);
+            },
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            flag if flag.starts_with("--") => return Err(format!("unknown option {}\n\n{}", flag, USAGE)),
+            file => {
+                if options.file.is_some() {
+                    return Err(format!("more than one input file\n\n{}", USAGE));
+                }
+                options.file = Some(file.to_string());
+            },
+        }
+    }
+    if options.emit.is_some() && options.run.is_some() {
+        return Err("--emit and --run cannot be used together".to_string());
+    }
+    if options.with_main && options.emit != Some(Emit::C) {
+        return Err("--with-main needs --emit c".to_string());
+    }
+    if options.float && matches!(options.emit, Some(Emit::X86) | Some(Emit::Wat) | Some(Emit::C)) {
+        return Err("--float only works with the stack machine".to_string());
+    }
+    Ok(options)
+}
+
+fn parse_integers(args: &[String]) -> Result<Vec<i64>, String> {
+    args.iter().map(|x| x.parse().map_err(|_| format!("{} is not an integer, did you mean --float?", x))).collect()
+}
+
+fn read_program(file: &Option<String>) -> Result<String, String> {
+    match file.as_deref() {
+        None | Some("-") => {
+            let mut program = String::new();
+            io::stdin().read_to_string(&mut program).map_err(|e| format!("cannot read standard input: {}", e))?;
+            Ok(program)
+        },
+        Some(path) => fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path, e)),
+    }
+}
+
+// A kata program is shown as the AST of its only function.
+fn show(program: &Program, json: bool) -> String {
+    match (program.functions.as_slice(), json) {
+        ([main], true) => main.body.to_json(),
+        ([main], false) => main.body.to_string(),
+        (_, true) => program.to_json(),
+        (_, false) => program.to_string().trim_end().to_string(),
+    }
+}
+
+fn dump(program: &Program, pass: &str, format: DumpFormat) {
+    match format {
+        DumpFormat::SExpr => eprint!(";; after {}\n{}", pass, program.to_pretty()),
+        DumpFormat::Dot => eprint!("// after {}\n{}", pass, program.to_dot()),
+    }
+}
+
+fn run(args: &[String]) -> Result<String, String> {
+    let options = parse_options(args)?;
+    let source = read_program(&options.file)?;
+    let mut compiler = Compiler::new();
+    compiler.codegen = options.codegen;
+    compiler.arithmetic = options.arithmetic;
+    compiler.inline = options.inline;
+    compiler.cse = options.cse;
+    compiler.float = options.float;
+    if options.strength_reduce {
+        compiler.passes.add("strength-reduction", StrengthReduction);
+    }
+    if options.stats {
+        return compiler.codegen_report(&source).map(|report| report.trim_end().to_string()).map_err(|e| e.describe(&source));
+    }
+
+    let emit = options.emit.unwrap_or(Emit::Asm);
+    let tokens = compiler.try_tokenize(&source).map_err(|e| format!("{}\n{}", e.describe(&source), e.span().underline(&source)))?;
+    if emit == Emit::Tokens {
+        return Ok(tokens.join("\n"));
+    }
+    let program = compiler.try_pass1_program(&source).map_err(|e| match e.span() {
+        Some(span) => format!("{}\n{}", e.describe(&source), span.underline(&source)),
+        None => e.to_string(),
+    })?;
+    if options.dump_after.contains(&Pass::Pass1) {
+        dump(&program, "pass1", options.dump_format);
+    }
+    if emit == Emit::Ast {
+        return Ok(show(&program, options.json));
+    }
+    let program = compiler.pass2_program(&program).map_err(|e| e.to_string())?;
+    if options.dump_after.contains(&Pass::Pass2) {
+        dump(&program, "pass2", options.dump_format);
+    }
+    if options.dump_after.contains(&Pass::Pass3) {
+        eprintln!(";; after pass3\n{}", three_address(&compiler.pass3_program(&program)).join("\n"));
+    }
+    if emit == Emit::AstOpt {
+        return Ok(show(&program, options.json));
+    }
+    if emit == Emit::X86 {
+        return Ok(compiler.pass3_x86(&program, "f").trim_end().to_string());
+    }
+    if emit == Emit::Wat {
+        return Ok(compiler.pass3_wat(&program, "f").trim_end().to_string());
+    }
+    if emit == Emit::C {
+        return Ok(compiler.pass3_c(&program, "f", options.with_main).trim_end().to_string());
+    }
+    let (asm, spans) = compiler.pass3_program_with_spans(&program);
+    let arity = program.function("main").unwrap().arity;
+    match &options.run {
+        Some(run_args) if run_args.len() != arity => {
+            return Err(format!("the program takes {} arguments but {} were given", arity, run_args.len()));
+        },
+        _ => (),
+    }
+    match options.run {
+        Some(run_args) if options.float => {
+            let run_args: Vec<f64> = run_args.iter().map(|x| x.parse().unwrap()).collect();
+            Ok(simulate_f64(&asm, &run_args).to_string())
+        },
+        Some(run_args) => match try_simulate(&asm, &parse_integers(&run_args)?) {
+            Ok(result) => Ok(result.to_string()),
+            Err(e) => match spans[e.address()] {
+                Some(span) => {
+                    let (line, column) = span.line_column(&source);
+                    Err(format!("{} at line {}, column {}\n{}", e.message(), line, column, span.underline(&source)))
+                },
+                None => Err(e.to_string()),
+            },
+        },
+        None if options.interleave => Ok(interleave(&source, &asm, &spans).trim_end().to_string()),
+        None => Ok(asm.join("\n")),
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match run(&args) {
+        Ok(output) => println!("{}", output),
+        Err(message) => {
+            eprintln!("tpc: {}", message);
+            process::exit(1);
+        },
+    }
+}
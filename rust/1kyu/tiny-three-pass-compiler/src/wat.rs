@@ -0,0 +1,115 @@
+// WebAssembly text format. `main` is exported under the given name and every function takes
+// one i64 parameter per argument. WebAssembly is a stack machine as well, so each node maps
+// to a few instructions; let-bound variables become locals numbered after the parameters.
+
+use crate::{Ast, BinOp, Leaf, Program};
+
+// The number of variables in scope at the deepest point of the tree.
+fn let_depth(ast: &Ast) -> usize {
+    match ast {
+        Ast::UnOp(..) => 0,
+        Ast::BinOp(_, lhs, rhs) => let_depth(lhs).max(let_depth(rhs)),
+        Ast::Let(value, body) => let_depth(value).max(1 + let_depth(body)),
+        Ast::Call(_, args) => args.iter().map(let_depth).max().unwrap_or(0),
+        Ast::If(cond, then, other) => let_depth(cond).max(let_depth(then)).max(let_depth(other)),
+        Ast::At(_, ast) => let_depth(ast),
+    }
+}
+
+struct Emitter {
+    output: String,
+    indent: usize,
+    arity: usize,
+    depth: usize,
+}
+
+impl Emitter {
+    fn line(&mut self, instr: &str) {
+        self.output.push_str(&"  ".repeat(self.indent));
+        self.output.push_str(instr);
+        self.output.push('\n');
+    }
+
+    fn emit(&mut self, ast: &Ast) {
+        match ast {
+            Ast::UnOp(Leaf::Imm, x) => self.line(&format!("i64.const {}", x)),
+            Ast::UnOp(Leaf::Arg, index) => self.line(&format!("local.get {}", index)),
+            Ast::UnOp(Leaf::Var, level) => self.line(&format!("local.get {}", self.arity + *level as usize)),
+            Ast::UnOp(Leaf::Float, _) => panic!("the WebAssembly backend has no float mode"),
+            Ast::BinOp(op, lhs, rhs) => {
+                self.emit(lhs);
+                self.emit(rhs);
+                let (instr, comparison) = match op {
+                    BinOp::Add => ("i64.add", false),
+                    BinOp::Sub => ("i64.sub", false),
+                    BinOp::Mul => ("i64.mul", false),
+                    BinOp::Div => ("i64.div_s", false),
+                    BinOp::Mod => ("i64.rem_s", false),
+                    BinOp::Lt => ("i64.lt_s", true),
+                    BinOp::Le => ("i64.le_s", true),
+                    BinOp::Gt => ("i64.gt_s", true),
+                    BinOp::Ge => ("i64.ge_s", true),
+                    BinOp::Eq => ("i64.eq", true),
+                    BinOp::Ne => ("i64.ne", true),
+                };
+                self.line(instr);
+                // comparisons produce an i32
+                if comparison {
+                    self.line("i64.extend_i32_u");
+                }
+            },
+            Ast::Let(value, body) => {
+                self.emit(value);
+                self.line(&format!("local.set {}", self.arity + self.depth));
+                self.depth += 1;
+                self.emit(body);
+                self.depth -= 1;
+            },
+            Ast::Call(name, args) => {
+                args.iter().for_each(|arg| self.emit(arg));
+                self.line(&format!("call ${}", name));
+            },
+            Ast::If(cond, then, other) => {
+                self.emit(cond);
+                self.line("i64.const 0");
+                self.line("i64.ne");
+                self.line("if (result i64)");
+                self.indent += 1;
+                self.emit(then);
+                self.indent -= 1;
+                self.line("else");
+                self.indent += 1;
+                self.emit(other);
+                self.indent -= 1;
+                self.line("end");
+            },
+            Ast::At(_, ast) => self.emit(ast),
+        }
+    }
+}
+
+pub(crate) fn emit_program(program: &Program, export: &str) -> String {
+    let mut emitter = Emitter { output: String::from("(module\n"), indent: 1, arity: 0, depth: 0 };
+    for function in &program.functions {
+        let mut header = format!("(func ${}", function.name);
+        if function.name == "main" {
+            header.push_str(&format!(" (export \"{}\")", export));
+        }
+        if function.arity > 0 {
+            header.push_str(&format!(" (param{})", " i64".repeat(function.arity)));
+        }
+        header.push_str(" (result i64)");
+        emitter.line(&header);
+        emitter.indent += 1;
+        let locals = let_depth(&function.body);
+        if locals > 0 {
+            emitter.line(&format!("(local{})", " i64".repeat(locals)));
+        }
+        emitter.arity = function.arity;
+        emitter.emit(&function.body);
+        emitter.indent -= 1;
+        emitter.line(")");
+    }
+    emitter.output.push_str(")\n");
+    emitter.output
+}
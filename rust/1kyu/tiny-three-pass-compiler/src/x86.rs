@@ -0,0 +1,164 @@
+// GNU-syntax x86-64 assembly for the System V ABI. The program becomes `long SYMBOL(long* args)`
+// and every other function `f` of it a local `SYMBOL_f` with the same signature.
+//
+// R0 of the stack machine is %rax and the argument pointer lives in %rbx. The stack holds the
+// same values as on the stack machine: operands waiting for their sibling, let-bound variables
+// and the arguments of a call, which are passed as a pointer to them.
+
+use std::convert::TryFrom;
+
+use crate::{Ast, BinOp, Leaf, Program};
+
+struct Emitter<'a> {
+    symbol: &'a str,
+    output: String,
+    // number of 8-byte values pushed since the prologue
+    depth: usize,
+    // stack slot of each let-bound variable, indexed by its level
+    slots: Vec<usize>,
+    labels: usize,
+}
+
+impl<'a> Emitter<'a> {
+    fn function_symbol(&self, name: &str) -> String {
+        if name == "main" {
+            self.symbol.to_string()
+        } else {
+            format!("{}_{}", self.symbol, name)
+        }
+    }
+
+    fn line(&mut self, instr: &str) {
+        self.output.push_str("    ");
+        self.output.push_str(instr);
+        self.output.push('\n');
+    }
+
+    fn label(&mut self) -> String {
+        self.labels += 1;
+        format!(".L{}", self.labels - 1)
+    }
+
+    // Slots lie below the saved %rbp and %rbx.
+    fn slot(slot: usize) -> String {
+        format!("{}(%rbp)", -16 - 8 * slot as i64)
+    }
+
+    fn function(&mut self, name: &str, body: &Ast) {
+        let symbol = self.function_symbol(name);
+        self.output.push_str(&format!("{}:\n", symbol));
+        self.line("pushq %rbp");
+        self.line("movq %rsp, %rbp");
+        self.line("pushq %rbx");
+        self.line("movq %rdi, %rbx");
+        self.emit(body);
+        self.line("popq %rbx");
+        self.line("popq %rbp");
+        self.line("ret");
+    }
+
+    fn emit(&mut self, ast: &Ast) {
+        match ast {
+            Ast::UnOp(Leaf::Imm, x) if i32::try_from(*x).is_ok() => self.line(&format!("movq ${}, %rax", x)),
+            Ast::UnOp(Leaf::Imm, x) => self.line(&format!("movabsq ${}, %rax", x)),
+            Ast::UnOp(Leaf::Arg, index) => self.line(&format!("movq {}(%rbx), %rax", 8 * index)),
+            Ast::UnOp(Leaf::Var, level) => self.line(&format!("movq {}, %rax", Emitter::slot(self.slots[*level as usize]))),
+            Ast::UnOp(Leaf::Float, _) => panic!("the x86-64 backend has no float mode"),
+            Ast::BinOp(op, lhs, rhs) => {
+                self.emit(lhs);
+                self.line("pushq %rax");
+                self.depth += 1;
+                self.emit(rhs);
+                self.line("movq %rax, %rcx");
+                self.line("popq %rax");
+                self.depth -= 1;
+                self.operate(*op);
+            },
+            Ast::Let(value, body) => {
+                self.emit(value);
+                self.slots.push(self.depth);
+                self.line("pushq %rax");
+                self.depth += 1;
+                self.emit(body);
+                self.slots.pop();
+                self.line("addq $8, %rsp");
+                self.depth -= 1;
+            },
+            Ast::Call(name, args) => {
+                // args[0] ends up at the lowest address, where %rsp points before the call
+                let base = self.depth;
+                if !args.is_empty() {
+                    self.line(&format!("subq ${}, %rsp", 8 * args.len()));
+                    self.depth += args.len();
+                }
+                for (i, arg) in args.iter().enumerate() {
+                    self.emit(arg);
+                    self.line(&format!("movq %rax, {}", Emitter::slot(base + args.len() - 1 - i)));
+                }
+                self.line("movq %rsp, %rdi");
+                // %rsp is 16-byte aligned at a call when an odd number of values is pushed
+                let padded = self.depth.is_multiple_of(2);
+                if padded {
+                    self.line("subq $8, %rsp");
+                }
+                let callee = self.function_symbol(name);
+                self.line(&format!("call {}", callee));
+                if padded {
+                    self.line("addq $8, %rsp");
+                }
+                if !args.is_empty() {
+                    self.line(&format!("addq ${}, %rsp", 8 * args.len()));
+                    self.depth -= args.len();
+                }
+            },
+            Ast::If(cond, then, other) => {
+                let (other_label, end_label) = (self.label(), self.label());
+                self.emit(cond);
+                self.line("testq %rax, %rax");
+                self.line(&format!("je {}", other_label));
+                self.emit(then);
+                self.line(&format!("jmp {}", end_label));
+                self.output.push_str(&format!("{}:\n", other_label));
+                self.emit(other);
+                self.output.push_str(&format!("{}:\n", end_label));
+            },
+            Ast::At(_, ast) => self.emit(ast),
+        }
+    }
+
+    // %rax = %rax op %rcx
+    fn operate(&mut self, op: BinOp) {
+        let set = match op {
+            BinOp::Add => return self.line("addq %rcx, %rax"),
+            BinOp::Sub => return self.line("subq %rcx, %rax"),
+            BinOp::Mul => return self.line("imulq %rcx, %rax"),
+            BinOp::Div | BinOp::Mod => {
+                self.line("cqto");
+                self.line("idivq %rcx");
+                if op == BinOp::Mod {
+                    self.line("movq %rdx, %rax");
+                }
+                return;
+            },
+            BinOp::Lt => "setl",
+            BinOp::Le => "setle",
+            BinOp::Gt => "setg",
+            BinOp::Ge => "setge",
+            BinOp::Eq => "sete",
+            BinOp::Ne => "setne",
+        };
+        self.line("cmpq %rcx, %rax");
+        self.line(&format!("{} %al", set));
+        self.line("movzbq %al, %rax");
+    }
+}
+
+pub(crate) fn emit_program(program: &Program, symbol: &str) -> String {
+    let mut emitter = Emitter { symbol, output: String::new(), depth: 0, slots: Vec::new(), labels: 0 };
+    emitter.output.push_str(&format!("    .text\n    .globl {}\n", symbol));
+    for function in &program.functions {
+        emitter.function(&function.name, &function.body);
+    }
+    emitter.output.push_str("    .section .note.GNU-stack,\"\",@progbits\n");
+    emitter.output
+}
@@ -0,0 +1,52 @@
+// Builds the C output, with its own `main`, using the system C compiler in strict C99 mode
+// with warnings as errors, and checks the results against the stack-machine simulator, both
+// for the program as parsed and as reduced by pass2.
+
+mod common;
+
+use std::fs;
+use std::path::PathBuf;
+
+use tiny_three_pass_compiler::{simulate, Compiler, Program};
+
+use common::{run, work_dir};
+
+fn build(compiler: &mut Compiler, program: &Program, name: &str) -> Option<PathBuf> {
+    let source = compiler.pass3_c(program, "f", true);
+    common::build(&work_dir(&format!("c-{}", name)), &["-std=c99", "-Wall", "-Wextra", "-pedantic", "-Werror"], &[("program.c", &source)])
+}
+
+#[test]
+fn c_code_matches_the_simulator() {
+    let cases: &[(&str, &[&[i64]])] = &[
+        ("[ x y z ] ( 2*3*x + 5*y - 3*z ) / (1 + 3 + 2*2)", &[&[4, 0, 0], &[4, 8, 16], &[-9, 2, 1]]),
+        ("[ a b ] -a % b - -(b % 3) + 7 / -2", &[&[-17, 5], &[17, -5]]),
+        ("[ a b ] let s = a + b in let d = a - b in (s * d) / (s - d + 1)", &[&[7, 3], &[1, 2]]),
+        ("[ a b ] let x = (let y = a / b in y * y) in let z = a * b in z * z - x + (let u = 1 / b in 2)", &[&[7, 3], &[-8, 2]]),
+        ("[ a b ] if a <= b then (if a == b then 0 else -1) else 1", &[&[1, 2], &[2, 2], &[3, 2]]),
+        ("[ a ] 0x7fffffffffffffff / (a * a + 1) + (a >= 0) + (a != 3) + (a < 0)", &[&[3], &[-1]]),
+        ("[ a ] a - 9223372036854775807 - 1", &[&[0], &[5]]),
+        ("def dist [x y] x - y; def main [a b c] dist(a, dist(b, c)) * dist(c, a)", &[&[10, 4, 1], &[0, 7, 2]]),
+        ("def fact [n] if n <= 1 then 1 else n * fact(n - 1); def main [n] fact(n) + fact(n - 1)", &[&[10], &[1]]),
+        ("def zero [] 0; def main [] zero() - 1", &[&[]]),
+    ];
+    for (i, (source, runs)) in cases.iter().enumerate() {
+        let mut compiler = Compiler::new();
+        let parsed = compiler.pass1_program(source);
+        let reduced = compiler.pass2_program(&parsed).unwrap();
+        let asm = compiler.pass3_program(&reduced);
+        for (program, stage) in &[(&parsed, "pass1"), (&reduced, "pass2")] {
+            let binary = match build(&mut compiler, program, &format!("{}-{}", i, stage)) {
+                Some(binary) => binary,
+                None => {
+                    eprintln!("no C compiler found, skipping the C test");
+                    return;
+                },
+            };
+            for args in runs.iter() {
+                assert_eq!(run(&binary, args), simulate(&asm, args), "{} {:?} after {}", source, args, stage);
+            }
+            fs::remove_dir_all(binary.parent().unwrap()).unwrap();
+        }
+    }
+}
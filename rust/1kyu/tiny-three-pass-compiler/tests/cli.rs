@@ -0,0 +1,113 @@
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+fn tpc(args: &[&str], program: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_tpc"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(program.as_bytes()).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+#[test]
+fn emit_stops_after_each_pass() {
+    let program = "[ a b ] (a + 2) + 3 * b";
+    assert_eq!(stdout(&tpc(&["--emit", "tokens"], program)), "[\na\nb\n]\n(\na\n+\n2\n)\n+\n3\n*\nb\n");
+    assert_eq!(stdout(&tpc(&["--emit", "ast"], program)), "(+ (+ (arg 0) (imm 2)) (* (imm 3) (arg 1)))\n");
+    assert_eq!(stdout(&tpc(&["--emit", "ast-opt", "--json"], "[ a ] a + 1 + 2")), "{\"op\":\"+\",\"a\":{\"op\":\"arg\",\"n\":0},\"b\":{\"op\":\"imm\",\"n\":3}}\n");
+    assert_eq!(stdout(&tpc(&["--codegen", "sethi-ullman"], "[ a ] a * 2")), "IM 2\nSW\nAR 0\nMU\n");
+    assert_eq!(
+        stdout(&tpc(&["--emit", "ast-opt", "--inline"], "def sq [x] x * x; def main [a] sq(a + 1) - sq(2)")),
+        "(- (let (+ (arg 0) (imm 1)) (* (var 0) (var 0))) (imm 4))\n",
+    );
+    assert_eq!(
+        stdout(&tpc(&["--emit", "ast-opt", "--cse"], "[ a ] (a + 1) / (a + 1)")),
+        "(let (+ (arg 0) (imm 1)) (/ (var 0) (var 0)))\n",
+    );
+    assert_eq!(stdout(&tpc(&["--float", "--run", "7", "0.5"], "[ a b ] a / 2 + b")), "4\n");
+    assert_eq!(stdout(&tpc(&["--float", "--run", "0"], "[ a ] -a")), "-0\n");
+    assert_eq!(stdout(&tpc(&["--emit", "ast-opt", "--strength-reduce"], "[ a b ] a * 2 + b")), "(+ (+ (arg 0) (arg 0)) (arg 1))\n");
+    assert!(stdout(&tpc(&["--emit", "x86-64"], "[ a ] a * 2")).contains("    .globl f\nf:\n"));
+    assert!(stdout(&tpc(&["--emit", "c"], "[ a ] a * 2")).contains("int64_t f(const int64_t *args) {\n    return (args[0] * 2);\n}\n"));
+    assert!(!tpc(&["--with-main"], "[ a ] a").status.success());
+    assert_eq!(
+        stdout(&tpc(&["--stats"], "[ a ] a * 2")),
+        "Naive: 9 instructions, max stack depth 1\nSethiUllman: 4 instructions, max stack depth 0\n",
+    );
+}
+
+#[test]
+fn run_executes_the_program() {
+    assert_eq!(stdout(&tpc(&["--run", "3", "4"], "[ a b ] a * a + b * b")), "25\n");
+    assert_eq!(stdout(&tpc(&["-", "--run", "-6"], "[ a ] a / 4")), "-1\n");
+    assert_eq!(stdout(&tpc(&["--run", "3", "4"], "def sq [x] x * x; def main [a b] sq(a) + sq(b)")), "25\n");
+    assert_eq!(stdout(&tpc(&["--run", "-5"], "[ a ] if a < 0 then -a else a")), "5\n");
+}
+
+#[test]
+fn dumps_go_to_standard_error() {
+    let output = tpc(&["--dump-after=pass1,pass2,pass3", "--run", "2"], "[ a ] a * (1 + 2)");
+    assert_eq!(stdout(&output), "6\n");
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        ";; after pass1\n(def main 1\n  (* (arg 0) (+ (imm 1) (imm 2))))\n\
+         ;; after pass2\n(def main 1\n  (* (arg 0) (imm 3)))\n\
+         ;; after pass3\n0: r0 = arg[0]\n1: r0, r1 = r1, r0\n2: s[0] = r0\n3: r0 = 3\n4: r0, r1 = r1, r0\n\
+         5: r0 = r0 * r1\n6: r0, r1 = r1, r0\n7: r0 = s[0]\n8: r0, r1 = r1, r0\n",
+    );
+    let output = tpc(&["--dump-after", "pass2", "--dump-format", "dot", "--emit=ast-opt"], "[ a ] a");
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "// after pass2\ndigraph program {\n  subgraph cluster_main {\n    label=\"main (1 arguments)\";\n    main_0 [label=\"arg 0\", shape=box];\n  }\n}\n",
+    );
+}
+
+#[test]
+fn errors_are_reported() {
+    let output = tpc(&[], "[] 1 / 0");
+    assert!(!output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "tpc: division by zero in constant expression (/ (imm 1) (imm 0))\n");
+    assert!(!tpc(&["--emit", "bytecode"], "[] 1").status.success());
+    assert!(!tpc(&["--run", "3"], "def main [a] a; 5").status.success());
+
+    let output = tpc(&["--emit", "tokens"], "[ a ]\n  a % 0x\n");
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "tpc: invalid literal 0x at line 2, column 7\n  a % 0x\n      ^^\n");
+
+    let output = tpc(&["--run", "3", "0"], "[ a b ] a + a / b");
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "tpc: division by zero at line 1, column 15\n[ a b ] a + a / b\n              ^\n");
+
+    let output = tpc(&["--run", "1"], "[ a ] a + b");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "tpc: undeclared identifier b at line 1, column 11\n[ a ] a + b\n          ^\n");
+    let output = tpc(&[], "[ a ] (a +");
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "tpc: expect a number, variable or parenthesised expression before the end of the input at line 1, column 11\n[ a ] (a +\n          ^\n");
+    let output = tpc(&[], "def f [x] x; def main [a] f(a, a)");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "tpc: f takes 1 arguments but 2 were given\n");
+    let output = tpc(&["--run", "1"], "[ a b ] a + b");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "tpc: the program takes 2 arguments but 1 were given\n");
+    let output = tpc(&["--run", "3000000000"], "[ a ] a * a * a");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "tpc: arithmetic overflow at line 1, column 13\n[ a ] a * a * a\n            ^\n");
+}
+
+#[test]
+fn interleave_shows_the_source_of_instructions() {
+    assert_eq!(
+        stdout(&tpc(&["--interleave", "--codegen", "sethi-ullman"], "[ a b ] a * b")),
+        ";; 1:13\n;; [ a b ] a * b\n;;             ^\n    AR 1\n\
+         ;; 1:11\n;; [ a b ] a * b\n;;           ^\n    SW\n\
+         ;; 1:9\n;; [ a b ] a * b\n;;         ^\n    AR 0\n\
+         ;; 1:11\n;; [ a b ] a * b\n;;           ^\n    MU\n",
+    );
+}
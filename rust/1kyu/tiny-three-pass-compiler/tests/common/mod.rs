@@ -0,0 +1,40 @@
+// Building and running native executables with the system C compiler, shared by the tests
+// of the backends whose output it compiles.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+// A fresh directory for the files of one build.
+pub fn work_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("tpc-{}-{}", name, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// Writes the named sources to `dir` and links them into an executable with the given flags,
+// or returns None when there is no C compiler.
+pub fn build(dir: &Path, flags: &[&str], sources: &[(&str, &str)]) -> Option<PathBuf> {
+    let paths: Vec<PathBuf> = sources.iter().map(|(name, text)| {
+        let path = dir.join(name);
+        fs::write(&path, text).unwrap();
+        path
+    }).collect();
+    let binary = dir.join("program");
+    let output = Command::new("cc").args(flags).arg("-o").arg(&binary).args(&paths).output();
+    match output {
+        Ok(output) => {
+            assert!(output.status.success(), "cannot build {}:\n{}", dir.display(), String::from_utf8_lossy(&output.stderr));
+            Some(binary)
+        },
+        Err(_) => None,
+    }
+}
+
+// Runs the executable with the arguments on its command line and parses what it prints.
+pub fn run(binary: &Path, args: &[i64]) -> i64 {
+    let output = Command::new(binary).args(args.iter().map(|x| x.to_string())).output().unwrap();
+    assert!(output.status.success());
+    String::from_utf8(output.stdout).unwrap().trim().parse().unwrap()
+}
@@ -0,0 +1,50 @@
+// Each case in tests/golden is a program NAME.txt with the expected output of every pass:
+// NAME.pass1.json, NAME.pass2.json and NAME.asm (one instruction per line).
+// Every pass is run on the golden output of the previous one, so a failure points at one pass.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use tiny_three_pass_compiler::{Ast, Compiler};
+
+fn cases() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("golden");
+    let mut cases: Vec<PathBuf> = fs::read_dir(dir).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "txt"))
+        .collect();
+    cases.sort();
+    assert!(!cases.is_empty());
+    cases
+}
+
+fn golden(case: &Path, extension: &str) -> String {
+    let path = case.with_extension(extension);
+    fs::read_to_string(&path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e)).trim().to_string()
+}
+
+#[test]
+fn pass1_matches_golden() {
+    for case in cases() {
+        let ast = Compiler::new().pass1(&golden(&case, "txt"));
+        assert_eq!(ast.to_json(), golden(&case, "pass1.json"), "{}", case.display());
+    }
+}
+
+#[test]
+fn pass2_matches_golden() {
+    for case in cases() {
+        let ast = Ast::from_json(&golden(&case, "pass1.json")).unwrap();
+        let ast = Compiler::new().pass2(&ast);
+        assert_eq!(ast.to_json(), golden(&case, "pass2.json"), "{}", case.display());
+    }
+}
+
+#[test]
+fn pass3_matches_golden() {
+    for case in cases() {
+        let ast = Ast::from_json(&golden(&case, "pass2.json")).unwrap();
+        let asm = Compiler::new().pass3(&ast).join("\n");
+        assert_eq!(asm, golden(&case, "asm"), "{}", case.display());
+    }
+}
@@ -0,0 +1,17 @@
+IM 3
+SW
+PU
+AR 0
+SW
+MU
+SW
+PO
+SW
+SW
+PU
+AR 1
+SW
+DI
+SW
+PO
+SW
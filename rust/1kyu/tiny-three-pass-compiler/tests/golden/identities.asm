@@ -0,0 +1,9 @@
+AR 0
+SW
+PU
+IM 5
+SW
+AD
+SW
+PO
+SW
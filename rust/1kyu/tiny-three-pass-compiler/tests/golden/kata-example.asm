@@ -0,0 +1,49 @@
+IM 6
+SW
+PU
+AR 0
+SW
+MU
+SW
+PO
+SW
+SW
+PU
+IM 5
+SW
+PU
+AR 1
+SW
+MU
+SW
+PO
+SW
+SW
+AD
+SW
+PO
+SW
+SW
+PU
+IM 3
+SW
+PU
+AR 2
+SW
+MU
+SW
+PO
+SW
+SW
+SU
+SW
+PO
+SW
+SW
+PU
+IM 8
+SW
+DI
+SW
+PO
+SW
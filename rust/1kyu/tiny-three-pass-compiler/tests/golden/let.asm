@@ -0,0 +1,29 @@
+AR 0
+SW
+PU
+AR 1
+SW
+AD
+SW
+PO
+SW
+PU
+LD 0
+SW
+PU
+LD 0
+SW
+MU
+SW
+PO
+SW
+SW
+PU
+IM 6
+SW
+SU
+SW
+PO
+SW
+ST 0
+PO
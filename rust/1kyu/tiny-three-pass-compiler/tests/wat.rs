@@ -0,0 +1,139 @@
+// Checks that the WebAssembly text output is well formed: every function body must leave
+// exactly one i64 on the operand stack and only refer to locals and functions that exist.
+// When `wasmtime` is installed the modules are also run and compared with the simulator.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::process::Command;
+
+use tiny_three_pass_compiler::{simulate, Compiler};
+
+struct Function {
+    arity: usize,
+    locals: usize,
+    body: Vec<String>,
+}
+
+fn count_i64(text: &str, keyword: &str) -> usize {
+    match text.find(&format!("({}", keyword)) {
+        Some(start) => text[start..].split(')').next().unwrap().matches("i64").count(),
+        None => 0,
+    }
+}
+
+fn functions(wat: &str) -> HashMap<String, Function> {
+    let lines: Vec<&str> = wat.lines().map(str::trim).collect();
+    assert_eq!(lines.first(), Some(&"(module"));
+    assert_eq!(lines.last(), Some(&")"));
+    let mut functions = HashMap::new();
+    let mut current: Option<(String, Function)> = None;
+    for line in &lines[1..lines.len() - 1] {
+        if let Some(header) = line.strip_prefix("(func $") {
+            assert!(current.is_none(), "nested function");
+            assert!(header.ends_with("(result i64)"), "{}", line);
+            let name = header.split(' ').next().unwrap().to_string();
+            current = Some((name, Function { arity: count_i64(line, "param"), locals: 0, body: Vec::new() }));
+        } else if *line == ")" {
+            let (name, function) = current.take().expect("unbalanced parenthesis");
+            functions.insert(name, function);
+        } else {
+            let function = &mut current.as_mut().expect("instruction outside a function").1;
+            if line.starts_with("(local") {
+                function.locals = count_i64(line, "local");
+            } else {
+                function.body.push(line.to_string());
+            }
+        }
+    }
+    assert!(current.is_none(), "unterminated function");
+    functions
+}
+
+// Operand stack depth after each instruction, with `if ... else ... end` blocks that each
+// consume the condition and produce one value.
+fn validate(name: &str, function: &Function, functions: &HashMap<String, Function>) {
+    let mut depth = 0i64;
+    let mut blocks: Vec<i64> = Vec::new();
+    for instr in &function.body {
+        let mut parts = instr.split(' ');
+        let op = parts.next().unwrap();
+        let operand = parts.next();
+        depth += match op {
+            "i64.const" => 1,
+            "local.get" | "local.set" => {
+                let index: usize = operand.unwrap().parse().unwrap();
+                assert!(index < function.arity + function.locals, "{}: no local {}", name, index);
+                if op == "local.get" { 1 } else { -1 }
+            },
+            "call" => {
+                let callee = &functions[operand.unwrap().trim_start_matches('$')];
+                1 - callee.arity as i64
+            },
+            "i64.extend_i32_u" => 0,
+            "if" => {
+                blocks.push(depth - 1);
+                -depth
+            },
+            "else" => {
+                assert!(!blocks.is_empty(), "else outside if");
+                assert_eq!(depth, 1, "{}: then branch leaves {} values", name, depth);
+                -depth
+            },
+            "end" => {
+                let start = blocks.pop().expect("end outside if");
+                assert_eq!(depth, 1, "{}: else branch leaves {} values", name, depth);
+                start
+            },
+            _ if op.starts_with("i64.") => -1,
+            _ => panic!("{}: unexpected instruction {}", name, instr),
+        };
+        assert!(depth >= 0, "{}: stack underflow at {}", name, instr);
+    }
+    assert!(blocks.is_empty(), "{}: unterminated if", name);
+    assert_eq!(depth, 1, "{} leaves {} values", name, depth);
+}
+
+fn wasmtime(wat: &str, args: &[i64]) -> Option<i64> {
+    let path = env::temp_dir().join(format!("tpc-{}.wat", std::process::id()));
+    fs::write(&path, wat).unwrap();
+    let output = Command::new("wasmtime")
+        .arg("--invoke").arg("f").arg(&path)
+        .args(args.iter().map(|x| x.to_string()))
+        .output();
+    fs::remove_file(&path).unwrap();
+    let output = output.ok()?;
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    Some(String::from_utf8(output.stdout).unwrap().trim().parse().unwrap())
+}
+
+#[test]
+fn wat_modules_are_well_formed() {
+    let cases: &[(&str, &[i64])] = &[
+        ("[ x y z ] ( 2*3*x + 5*y - 3*z ) / (1 + 3 + 2*2)", &[4, 8, 16]),
+        ("[ a b ] let s = a + b in let d = a - b in (s * d) / (s - d + 1)", &[7, 3]),
+        ("[ a b ] if a <= b then (if a == b then 0 else -1) else let c = a % b in c * c", &[9, 4]),
+        ("def fact [n] if n <= 1 then 1 else n * fact(n - 1); def main [n] fact(n) + fact(n - 1)", &[10]),
+        ("def zero [] 0; def main [] zero() - 1", &[]),
+    ];
+    for (source, args) in cases {
+        let mut compiler = Compiler::new();
+        let program = compiler.pass1_program(source);
+        let program = compiler.pass2_program(&program).unwrap();
+        let wat = compiler.pass3_wat(&program, "f");
+        assert!(wat.contains("(export \"f\")"), "{}", wat);
+
+        let functions = functions(&wat);
+        assert_eq!(functions.len(), program.functions.len());
+        for (name, function) in &functions {
+            assert_eq!(function.arity, program.function(name).unwrap().arity);
+            validate(name, function, &functions);
+        }
+
+        let expected = simulate(&compiler.pass3_program(&program), args);
+        match wasmtime(&wat, args) {
+            Some(result) => assert_eq!(result, expected, "{} {:?}", source, args),
+            None => eprintln!("wasmtime not found, skipping the run of {}", source),
+        }
+    }
+}
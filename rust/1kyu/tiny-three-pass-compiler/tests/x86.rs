@@ -0,0 +1,67 @@
+// Assembles the x86-64 output with the system C compiler, links it with a small driver that
+// reads the arguments from the command line, and checks the native results against the
+// stack-machine simulator, both for the program as parsed and as reduced by pass2.
+#![cfg(all(target_arch = "x86_64", target_os = "linux"))]
+
+mod common;
+
+use std::fs;
+use std::path::PathBuf;
+
+use tiny_three_pass_compiler::{simulate, Compiler, Program};
+
+use common::{run, work_dir};
+
+const DRIVER: &str = r#"
+#include <stdio.h>
+#include <stdlib.h>
+
+long f(long *args);
+
+int main(int argc, char **argv) {
+    long args[64];
+    for (int i = 1; i < argc && i <= 64; i++) {
+        args[i - 1] = strtol(argv[i], NULL, 10);
+    }
+    printf("%ld\n", f(args));
+    return 0;
+}
+"#;
+
+fn build(compiler: &mut Compiler, program: &Program, name: &str) -> Option<PathBuf> {
+    let assembly = compiler.pass3_x86(program, "f");
+    common::build(&work_dir(&format!("x86-{}", name)), &[], &[("driver.c", DRIVER), ("program.s", &assembly)])
+}
+
+#[test]
+fn native_code_matches_the_simulator() {
+    let cases: &[(&str, &[&[i64]])] = &[
+        ("[ x y z ] ( 2*3*x + 5*y - 3*z ) / (1 + 3 + 2*2)", &[&[4, 0, 0], &[4, 8, 16], &[-9, 2, 1]]),
+        ("[ a b ] -a % b - -(b % 3) + 7 / -2", &[&[-17, 5], &[17, -5]]),
+        ("[ a b ] let s = a + b in let d = a - b in (s * d) / (s - d + 1)", &[&[7, 3], &[1, 2]]),
+        ("[ a b ] if a <= b then (if a == b then 0 else -1) else 1", &[&[1, 2], &[2, 2], &[3, 2]]),
+        ("[ a ] 90000 * 100000 * a + (a >= 0) + (a != 3) + (a < 0) + (a > 1)", &[&[3], &[-1]]),
+        ("def dist [x y] x - y; def main [a b c] dist(a, dist(b, c)) * dist(c, a)", &[&[10, 4, 1], &[0, 7, 2]]),
+        ("def fact [n] if n <= 1 then 1 else n * fact(n - 1); def main [n] fact(n) + fact(n - 1)", &[&[10], &[1]]),
+        ("def zero [] 0; def main [a] let x = a * 2 in zero() + x + zero()", &[&[21]]),
+    ];
+    for (i, (source, runs)) in cases.iter().enumerate() {
+        let mut compiler = Compiler::new();
+        let parsed = compiler.pass1_program(source);
+        let reduced = compiler.pass2_program(&parsed).unwrap();
+        let asm = compiler.pass3_program(&reduced);
+        for (program, stage) in &[(&parsed, "pass1"), (&reduced, "pass2")] {
+            let binary = match build(&mut compiler, program, &format!("{}-{}", i, stage)) {
+                Some(binary) => binary,
+                None => {
+                    eprintln!("no C compiler found, skipping the x86-64 test");
+                    return;
+                },
+            };
+            for args in runs.iter() {
+                assert_eq!(run(&binary, args), simulate(&asm, args), "{} {:?} after {}", source, args, stage);
+            }
+            fs::remove_dir_all(binary.parent().unwrap()).unwrap();
+        }
+    }
+}
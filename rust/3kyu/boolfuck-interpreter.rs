@@ -1,3 +1,6 @@
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+
 const MASK_BEGIN: u8 = 0b_0000_0001_u8;
 
 const MASK_END: u8 = 0b_1000_0000_u8;
@@ -20,6 +23,12 @@ impl Pointer {
         data[self.index] & self.mask > 0
     }
 
+    // None past the end of the data, which is never grown for reading
+    #[inline]
+    fn try_get(&self, data: &[u8]) -> Option<bool> {
+        data.get(self.index).map(|&x| x & self.mask > 0)
+    }
+
     #[inline]
     fn set(&self, value: bool, data: &mut Vec<u8>) {
         if value {
@@ -176,60 +185,251 @@ impl Machine {
             self.on_right = true
         }
     }
+
+    // bytes of memory in use on both sides of the start
+    #[inline]
+    fn tape_size(&self) -> usize {
+        self.left.data.len() + self.right.data.len()
+    }
+}
+
+// Reads a byte only once its first bit is needed, so that interactive input works. Every byte
+// is a separate one-byte `read_exact`, so slow readers such as files are best wrapped in a
+// `BufReader` first.
+struct BitReader<R: Read> {
+    inner: R,
+    buffer: Vec<u8>,
+    pointer: Pointer,
+}
+
+impl<R: Read> BitReader<R> {
+    fn new(inner: R) -> BitReader<R> {
+        BitReader {
+            inner,
+            buffer: Vec::new(),
+            pointer: Pointer::new(),
+        }
+    }
+
+    // None at the end of the input
+    fn read(&mut self) -> io::Result<Option<bool>> {
+        if self.pointer.index == self.buffer.len() {
+            self.buffer.clear();
+            self.pointer.index = 0;
+            let mut byte = [0u8];
+            match self.inner.read_exact(&mut byte) {
+                Ok(()) => self.buffer.push(byte[0]),
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        }
+        let bit = self.pointer.try_get(&self.buffer);
+        self.pointer.move_far_no_check();
+        Ok(bit)
+    }
+}
+
+// Writes and flushes every byte as soon as its last bit is set, so that a prompt shows up
+// before the program waits for input.
+struct BitWriter<W: Write> {
+    inner: W,
+    buffer: Vec<u8>,
+    pointer: Pointer,
+}
+
+impl<W: Write> BitWriter<W> {
+    fn new(inner: W) -> BitWriter<W> {
+        BitWriter {
+            inner,
+            buffer: vec![0u8],
+            pointer: Pointer::new(),
+        }
+    }
+
+    fn write(&mut self, bit: bool) -> io::Result<()> {
+        self.pointer.set(bit, &mut self.buffer);
+        self.pointer.move_far(&mut self.buffer);
+        if self.pointer.index == 1 {
+            self.inner.write_all(&self.buffer[..1])?;
+            self.inner.flush()?;
+            self.buffer.remove(0);
+            self.pointer.index = 0;
+        }
+        Ok(())
+    }
+
+    // writes the last byte if it is partly set, padded with zeros
+    fn finish(mut self) -> io::Result<()> {
+        self.pointer.shrink_to_fit(&mut self.buffer);
+        self.inner.write_all(&self.buffer)?;
+        self.inner.flush()
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum BoolfuckError {
+    UnmatchedOpen(usize),
+    UnmatchedClose(usize),
+    EndOfInput(usize),
+    Io(io::ErrorKind),
+}
+
+impl From<io::Error> for BoolfuckError {
+    fn from(e: io::Error) -> BoolfuckError {
+        BoolfuckError::Io(e.kind())
+    }
+}
+
+// what `,` reads once the input is exhausted
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Eof {
+    Zero,
+    Error,
+}
+
+// unbounded by default
+#[derive(Clone, Copy, Debug)]
+struct RunLimits {
+    max_steps: u64,
+    max_tape: usize,
+}
+
+impl Default for RunLimits {
+    fn default() -> RunLimits {
+        RunLimits {
+            max_steps: u64::MAX,
+            max_tape: usize::MAX,
+        }
+    }
+}
+
+// why a run stopped before the program halted
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Stop {
+    Steps,
+    Tape,
+    Cancelled,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Run {
+    output: Vec<u8>,
+    stopped: Option<Stop>,
+}
+
+impl std::fmt::Display for BoolfuckError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BoolfuckError::UnmatchedOpen(i) => write!(f, "unmatched '[' at {}", i),
+            BoolfuckError::UnmatchedClose(i) => write!(f, "unmatched ']' at {}", i),
+            BoolfuckError::EndOfInput(i) => write!(f, "',' at {} reads past the end of the input", i),
+            BoolfuckError::Io(kind) => write!(f, "i/o error: {}", kind),
+        }
+    }
 }
 
-fn create_jump_table(code: &Vec<char>) -> Vec<Option<usize>> {
-    let mut jump_table: Vec<Option<usize>> = Vec::new();
+impl std::error::Error for BoolfuckError {}
+
+// positions are the char indices of the brackets in the unfiltered source
+fn create_jump_table(code: &[char], positions: &[usize]) -> Result<Vec<usize>, BoolfuckError> {
+    let mut jump_table: Vec<usize> = Vec::new();
     let mut left_stack: Vec<usize> = Vec::new();
     for (i, &ch) in code.iter().enumerate() {
         match ch {
             '[' => {
                 left_stack.push(i);
-                jump_table.push(None);
+                jump_table.push(0);
             },
             ']' => {
-                let h = left_stack.pop().unwrap();
-                jump_table[h] = Some(i);
-                jump_table.push(Some(h));
+                let h = left_stack.pop().ok_or(BoolfuckError::UnmatchedClose(positions[i]))?;
+                jump_table[h] = i;
+                jump_table.push(h);
             },
-            _ => jump_table.push(None),
+            _ => jump_table.push(0),
         }
     }
-    jump_table
+    match left_stack.pop() {
+        Some(h) => Err(BoolfuckError::UnmatchedOpen(positions[h])),
+        None => Ok(jump_table),
+    }
 }
 
 fn boolfuck(code_str: &str, input: Vec<u8>) -> Vec<u8> {
-    let code: Vec<char> = code_str.chars().filter(|&x| match x {
-        '+' | ',' | ';' | '<' | '>' | '[' | ']' => true,
-        _ => false,
-    }).collect();
-    let jump_table = create_jump_table(&code);
+    try_boolfuck(code_str, input).unwrap_or_else(|e| panic!("{}", e))
+}
+
+fn try_boolfuck(code_str: &str, input: Vec<u8>) -> Result<Vec<u8>, BoolfuckError> {
+    try_boolfuck_with(code_str, input, Eof::Zero)
+}
+
+fn try_boolfuck_with(code_str: &str, input: Vec<u8>, eof: Eof) -> Result<Vec<u8>, BoolfuckError> {
+    let run = run_limited(code_str, input, eof, RunLimits::default(), &AtomicBool::new(false))?;
+    Ok(run.output)
+}
+
+// Runs until the program halts, runs out of steps or tape, or `cancel` is set, and returns
+// the output written so far.
+fn run_limited(code_str: &str, input: Vec<u8>, eof: Eof, limits: RunLimits, cancel: &AtomicBool) -> Result<Run, BoolfuckError> {
+    let mut output = Vec::new();
+    let stopped = boolfuck_stream(code_str, &input[..], &mut output, eof, limits, cancel)?;
+    Ok(Run { output, stopped })
+}
+
+// Like `run_limited`, reading the input and writing the output as the program goes.
+fn boolfuck_stream<R: Read, W: Write>(
+    code_str: &str,
+    input: R,
+    output: W,
+    eof: Eof,
+    limits: RunLimits,
+    cancel: &AtomicBool,
+) -> Result<Option<Stop>, BoolfuckError> {
+    let (positions, code): (Vec<usize>, Vec<char>) = code_str.chars().enumerate().filter(|&(_, x)| matches!(x, '+' | ',' | ';' | '<' | '>' | '[' | ']')).unzip();
+    let jump_table = create_jump_table(&code, &positions)?;
     let mut machine = Machine::new();
-    let mut read_pointer = Pointer::new();
-    let mut write_pointer = Pointer::new();
-    let mut output: Vec<u8> = vec![0u8];
+    let mut reader = BitReader::new(input);
+    let mut writer = BitWriter::new(output);
     let mut pc = 0usize;
+    let mut steps = 0u64;
+    let mut stopped = None;
     while pc < code.len() {
+        if steps == limits.max_steps {
+            stopped = Some(Stop::Steps);
+            break;
+        }
+        if cancel.load(Ordering::Relaxed) {
+            stopped = Some(Stop::Cancelled);
+            break;
+        }
+        steps += 1;
         let mut should_inc_pc = true;
         match code[pc] {
             '+' => {
                 machine.flip();
             },
             ',' => {
-                machine.set(read_pointer.get(&input));
-                read_pointer.move_far_no_check();
+                let bit = match (reader.read()?, eof) {
+                    (Some(bit), _) => bit,
+                    (None, Eof::Zero) => false,
+                    (None, Eof::Error) => return Err(BoolfuckError::EndOfInput(positions[pc])),
+                };
+                machine.set(bit);
             },
             ';' => {
-                write_pointer.set(machine.get(), &mut output);
-                write_pointer.move_far(&mut output);
+                writer.write(machine.get())?;
+            },
+            '<' | '>' => {
+                if code[pc] == '<' { machine.move_left() } else { machine.move_right() }
+                if machine.tape_size() > limits.max_tape {
+                    stopped = Some(Stop::Tape);
+                    break;
+                }
             },
-            '<' => { machine.move_left() },
-            '>' => { machine.move_right() },
             '[' => if !machine.get() {
-                pc = jump_table[pc].unwrap();
+                pc = jump_table[pc];
             },
             ']' => if machine.get() {
-                pc = jump_table[pc].unwrap();
+                pc = jump_table[pc];
                 should_inc_pc = false;
             },
             _ => continue,
@@ -238,6 +438,107 @@ fn boolfuck(code_str: &str, input: Vec<u8>) -> Vec<u8> {
             pc += 1;
         }
     }
-    write_pointer.shrink_to_fit(&mut output);
-    output
+    writer.finish()?;
+    Ok(stopped)
+}
+
+#[test]
+fn reads_past_the_end_of_the_input() {
+    let echo = ">,>,>,>,>,>,>,>,<<<<<<<;>;>;>;>;>;>;>;";
+    assert_eq!(boolfuck(echo, b"Codewars".to_vec()), b"C".to_vec());
+    assert_eq!(boolfuck(echo, vec![]), vec![0]);
+    assert_eq!(boolfuck(",;,;,;,;,;,;,;,;", vec![]), vec![0]);
+    assert_eq!(try_boolfuck_with(echo, vec![], Eof::Error), Err(BoolfuckError::EndOfInput(1)));
+
+    // the second byte is read a bit at a time, past the only one there is
+    let twice = ",;,;,;,;,;,;,;,; ,;,;,;,;,;,;,;,;";
+    assert_eq!(boolfuck(twice, vec![0xa5]), vec![0xa5, 0]);
+    assert_eq!(try_boolfuck_with(twice, vec![0xa5], Eof::Error), Err(BoolfuckError::EndOfInput(17)));
+    assert_eq!(try_boolfuck_with(twice, vec![0xa5, 0x3c], Eof::Error), Ok(vec![0xa5, 0x3c]));
+}
+
+#[test]
+fn limits_stop_the_run_with_the_output_so_far() {
+    let limits = RunLimits { max_steps: 1000, ..RunLimits::default() };
+    let never = AtomicBool::new(false);
+    let run = run_limited(";;+;+;;;;;+[]", vec![], Eof::Zero, limits, &never).unwrap();
+    assert_eq!(run, Run { output: vec![0b_0000_0100], stopped: Some(Stop::Steps) });
+    let run = run_limited("+;", vec![], Eof::Zero, limits, &never).unwrap();
+    assert_eq!(run, Run { output: vec![1], stopped: None });
+
+    let limits = RunLimits { max_tape: 4, ..RunLimits::default() };
+    let run = run_limited("+[>+]", vec![], Eof::Zero, limits, &never).unwrap();
+    assert_eq!(run.stopped, Some(Stop::Tape));
+    let run = run_limited("+[<+]", vec![], Eof::Zero, limits, &never).unwrap();
+    assert_eq!(run.stopped, Some(Stop::Tape));
+
+    let run = run_limited("+[]", vec![], Eof::Zero, RunLimits::default(), &AtomicBool::new(true)).unwrap();
+    assert_eq!(run, Run { output: vec![], stopped: Some(Stop::Cancelled) });
+}
+
+#[test]
+fn streams_read_and_write_bytes_as_they_are_needed() {
+    // reads one byte per call, and nothing past what the program asks for
+    struct Trickle<'a>(&'a [u8], usize);
+
+    impl<'a> Read for Trickle<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.get(self.1) {
+                Some(&x) if !buf.is_empty() => {
+                    buf[0] = x;
+                    self.1 += 1;
+                    Ok(1)
+                },
+                _ => Ok(0),
+            }
+        }
+    }
+
+    let never = AtomicBool::new(false);
+    // copies 12 bits, across a byte boundary on both sides
+    let copy = ",;".repeat(12);
+    let mut input = Trickle(&[0xa5, 0x3c, 0xff], 0);
+    let mut output = Vec::new();
+    let stopped = boolfuck_stream(&copy, &mut input, &mut output, Eof::Zero, RunLimits::default(), &never).unwrap();
+    assert_eq!((stopped, input.1), (None, 2));
+    assert_eq!(output, vec![0xa5, 0x0c]);
+
+    struct Closed;
+
+    impl Write for Closed {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::BrokenPipe, "closed"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // the length of the output at every flush
+    struct Flushes(Vec<u8>, Vec<usize>);
+
+    impl Write for Flushes {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.1.push(self.0.len());
+            Ok(())
+        }
+    }
+
+    let mut output = Flushes(Vec::new(), Vec::new());
+    boolfuck_stream(&";".repeat(20), io::empty(), &mut output, Eof::Zero, RunLimits::default(), &never).unwrap();
+    assert_eq!(output.1, vec![1, 2, 3]);
+
+    let result = boolfuck_stream(&";".repeat(8), io::empty(), Closed, Eof::Zero, RunLimits::default(), &never);
+    assert_eq!(result, Err(BoolfuckError::Io(io::ErrorKind::BrokenPipe)));
+}
+
+#[test]
+fn unmatched_brackets_are_errors() {
+    assert_eq!(try_boolfuck("+ ]", vec![]), Err(BoolfuckError::UnmatchedClose(2)));
+    assert_eq!(try_boolfuck("a[+[]", vec![]), Err(BoolfuckError::UnmatchedOpen(1)));
 }